@@ -2,24 +2,40 @@
 
 use super::*;
 use anyhow::{Context as _, Result};
-use std::{
-    fs::write,
-    fs::File,
-    io::Write,
-    path::{Path, PathBuf},
-};
+use std::{collections::HashSet, fs::write, fs::File, io::Write, path::Path};
 use time::OffsetDateTime;
 mod static_files;
 
 /// A HTML renderer.
 pub struct Renderer<'a> {
-    dir: &'a Path,
+    /// The documentation directory, or the output file for a single page.
+    path: &'a Path,
+    /// Include the source of declarations.
+    pub source_snippets: bool,
+    /// Names of all documented types, which are linked wherever they are used.
+    types: HashSet<String>,
+    /// The sections rendered so far, if all documentation goes into a single page.
+    single_page: Option<Vec<Vec<u8>>>,
 }
 
 impl<'a> Renderer<'a> {
     /// Create a new HTML renderer.
     pub fn new(dir: &'a Path) -> Self {
-        Self { dir }
+        Self {
+            path: dir,
+            source_snippets: false,
+            types: HashSet::new(),
+            single_page: None,
+        }
+    }
+
+    /// Create a new HTML renderer that writes a single, self-contained page with the stylesheets
+    /// inlined. Pages are rendered as sections and linked through anchors.
+    pub fn single_page(file: &'a Path) -> Self {
+        Self {
+            single_page: Some(vec![]),
+            ..Self::new(file)
+        }
     }
 
     pub fn render_header(&mut self, out: &mut impl Write) -> Result<()> {
@@ -31,6 +47,14 @@ impl<'a> Renderer<'a> {
         )
         .unwrap();
         writeln!(out, "<html>")?;
+        if self.single_page.is_some() {
+            writeln!(out, "<style>")?;
+            writeln!(out, "{}", static_files::RUSTDOC_CSS)?;
+            writeln!(out, "{}", static_files::LIGHT)?;
+            writeln!(out, "{}", static_files::SVDOC_CSS)?;
+            writeln!(out, "</style>")?;
+            return Ok(());
+        }
         writeln!(
             out,
             "<link rel=\"stylesheet\" type=\"text/css\" href=\"static/rustdoc.css\">"
@@ -50,14 +74,56 @@ impl<'a> Renderer<'a> {
     pub fn render(&mut self, doc: &Doc) -> Result<()> {
         debug!("Render documentation");
 
+        if self.single_page.is_none() {
+            self.write_static_files()?;
+        }
+
+        fn collect_types(cx: &Context, types: &mut HashSet<String>) {
+            types.extend(cx.types.iter().map(|i| i.name.clone()));
+            let nested = cx
+                .packages
+                .iter()
+                .map(|i| &i.content)
+                .chain(cx.modules.iter().map(|i| &i.content))
+                .chain(cx.interfaces.iter().map(|i| &i.content));
+            for cx in nested {
+                collect_types(cx, types);
+            }
+        }
+        collect_types(&doc.data, &mut self.types);
+
+        // Render the index.
+        self.render_index(doc)
+            .with_context(|| "Failed to render index")?;
+        self.render_all_items(doc)
+            .with_context(|| "Failed to render list of all items")?;
+
+        // Assemble the single page from the rendered sections.
+        if let Some(sections) = self.single_page.as_mut().map(std::mem::take) {
+            let mut out = File::create(self.path)
+                .with_context(|| format!("Failed to create `{}`", self.path.display()))?;
+            self.render_header(&mut out)?;
+            writeln!(out, "<body>")?;
+            for section in sections {
+                out.write_all(&section)?;
+            }
+            writeln!(out, "</body>")?;
+            writeln!(out, "</html>")?;
+        }
+
+        Ok(())
+    }
+
+    /// Create the documentation directory and write the stylesheets and fonts.
+    fn write_static_files(&mut self) -> Result<()> {
         // Create the documentation directory.
-        std::fs::create_dir_all(self.dir)
-            .with_context(|| format!("Failed to create doc directory `{}`", self.dir.display()))?;
+        std::fs::create_dir_all(self.path)
+            .with_context(|| format!("Failed to create doc directory `{}`", self.path.display()))?;
 
         // Write the static files.
-        let mut static_path = self.dir.join("static");
+        let mut static_path = self.path.join("static");
         std::fs::create_dir_all(&mut static_path)
-            .with_context(|| format!("Failed to create doc directory `{}`", self.dir.display()))?;
+            .with_context(|| format!("Failed to create doc directory `{}`", self.path.display()))?;
 
         write(static_path.join("light.css"), static_files::LIGHT)?;
         write(static_path.join("rustdoc.css"), static_files::RUSTDOC_CSS)?;
@@ -93,40 +159,154 @@ impl<'a> Renderer<'a> {
             static_files::fira_sans::MEDIUM,
         )?;
 
-        // Render the index.
-        self.render_index(doc)
-            .with_context(|| "Failed to render index")?;
-
         Ok(())
     }
 
-    fn render_index(&mut self, doc: &Doc) -> Result<()> {
-        let path = self.dir.join("index.html");
-        debug!("Render index into `{}`", path.display());
-        let mut out = File::create(path)?;
-
+    /// Write a rendered page, either into its own file or as a section of the single page.
+    fn write_page(&mut self, subpath: &str, content: Vec<u8>) -> Result<()> {
+        if let Some(sections) = &mut self.single_page {
+            let id = subpath.trim_start_matches('#');
+            let mut section = format!("<section id=\"{}\" class=\"content\">", id).into_bytes();
+            section.extend(content);
+            section.extend(b"</section>\n");
+            // The index opens the page, even though it is finished after the pages it links.
+            match id == "main" {
+                true => sections.insert(0, section),
+                false => sections.push(section),
+            }
+            return Ok(());
+        }
+        let path = self.path.join(subpath);
+        let mut out = File::create(&path)
+            .with_context(|| format!("Failed to create `{}`", path.display()))?;
         self.render_header(&mut out)?;
         writeln!(out, "<body>")?;
         write!(out, "<section id=\"main\" class=\"content\">")?;
-        writeln!(out, "<h1 class=\"fqn\">Documentation</h1>")?;
-
-        self.render_contents(&doc.data, &mut out)?;
-
+        out.write_all(&content)?;
         writeln!(out, "</section>")?;
         writeln!(out, "</body>")?;
         writeln!(out, "</html>")?;
-
         Ok(())
     }
 
+    fn render_index(&mut self, doc: &Doc) -> Result<()> {
+        let subpath = self.subpath_to_index();
+        debug!("Render index into `{}`", subpath);
+        let mut out = vec![];
+
+        writeln!(out, "<h1 class=\"fqn\">Documentation</h1>")?;
+        writeln!(
+            out,
+            "<p><a href=\"{}\">List of all items</a></p>",
+            self.subpath_to_all_items()
+        )?;
+
+        self.render_contents(&doc.data, &subpath, &mut out)?;
+
+        if !doc.omitted.is_empty() {
+            writeln!(
+                out,
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Omitted Files</a></h2>",
+                self.anchor_id(&subpath, "omitted")
+            )?;
+            writeln!(
+                out,
+                "<div class=\"docblock\"><p>The following files could not be parsed and are not documented:</p></div>"
+            )?;
+            writeln!(out, "<ul>")?;
+            for path in &doc.omitted {
+                writeln!(out, "<li><code>{}</code></li>", crate::xml_escape(path))?;
+            }
+            writeln!(out, "</ul>")?;
+        }
+
+        self.write_page(&subpath, out)
+    }
+
+    /// Render an alphabetical list of all packages, modules, and types, including those nested
+    /// in other declarations.
+    fn render_all_items(&mut self, doc: &Doc) -> Result<()> {
+        let subpath = self.subpath_to_all_items();
+        debug!("Render list of all items into `{}`", subpath);
+        let mut out = vec![];
+
+        let mut packages = vec![];
+        let mut modules = vec![];
+        let mut interfaces = vec![];
+        let mut types = vec![];
+        fn collect<'a>(
+            cx: &'a Context,
+            packages: &mut Vec<&'a str>,
+            modules: &mut Vec<&'a str>,
+            interfaces: &mut Vec<&'a str>,
+            types: &mut Vec<&'a str>,
+        ) {
+            packages.extend(cx.packages.iter().map(|i| i.name.as_str()));
+            modules.extend(cx.modules.iter().map(|i| i.name.as_str()));
+            interfaces.extend(cx.interfaces.iter().map(|i| i.name.as_str()));
+            types.extend(cx.types.iter().map(|i| i.name.as_str()));
+            let nested = cx
+                .packages
+                .iter()
+                .map(|i| &i.content)
+                .chain(cx.modules.iter().map(|i| &i.content))
+                .chain(cx.interfaces.iter().map(|i| &i.content));
+            for cx in nested {
+                collect(cx, packages, modules, interfaces, types);
+            }
+        }
+        collect(
+            &doc.data,
+            &mut packages,
+            &mut modules,
+            &mut interfaces,
+            &mut types,
+        );
+
+        writeln!(out, "<h1 class=\"fqn\">List of all items</h1>")?;
+        let sections = [
+            ("packages", "Packages", "package", packages),
+            ("modules", "Modules", "module", modules),
+            ("interfaces", "Interfaces", "interface", interfaces),
+            ("types", "Types", "type", types),
+        ];
+        for (id, title, class, mut names) in sections {
+            if names.is_empty() {
+                continue;
+            }
+            names.sort_unstable_by_key(|x| x.to_lowercase());
+            names.dedup();
+            writeln!(
+                out,
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">{1}</a></h2>",
+                self.anchor_id(&subpath, id),
+                title
+            )?;
+            writeln!(out, "<ul class=\"index\">")?;
+            for name in names {
+                let href = match class {
+                    "package" => self.subpath_to_package(name),
+                    "module" => self.subpath_to_module(name),
+                    "interface" => self.subpath_to_interface(name),
+                    _ => self.subpath_to_type(name),
+                };
+                writeln!(
+                    out,
+                    "<li><a class=\"{}\" href=\"{}\">{}</a></li>",
+                    class, href, name
+                )?;
+            }
+            writeln!(out, "</ul>")?;
+        }
+
+        self.write_page(&subpath, out)
+    }
+
     fn render_package(&mut self, item: &PackageItem) -> Result<()> {
-        let path = self.path_to_package(&item.name);
-        debug!("Render package `{}` into `{}`", item.name, path.display());
-        let mut out = File::create(path)?;
+        let subpath = self.subpath_to_package(&item.name);
+        debug!("Render package `{}` into `{}`", item.name, subpath);
+        let mut out = vec![];
 
-        self.render_header(&mut out)?;
-        writeln!(out, "<body>")?;
-        write!(out, "<section id=\"main\" class=\"content\">")?;
         writeln!(
             out,
             "<h1 class=\"fqn\">Package <a class=\"package\">{}</a></h1>",
@@ -137,23 +317,18 @@ impl<'a> Renderer<'a> {
         self.render_doc(&item.doc, &mut out)?;
         writeln!(out, "</div>")?;
 
-        self.render_contents(&item.content, &mut out)?;
+        self.render_index_of_contents(&item.content, &subpath, &mut out)?;
+        self.render_contents(&item.content, &subpath, &mut out)?;
+        self.render_source(&item.source, &subpath, &mut out)?;
 
-        writeln!(out, "</section>")?;
-        writeln!(out, "</body>")?;
-        writeln!(out, "</html>")?;
-
-        Ok(())
+        self.write_page(&subpath, out)
     }
 
     fn render_module(&mut self, item: &ModuleItem) -> Result<()> {
-        let path = self.path_to_module(&item.name);
-        debug!("Render module `{}` into `{}`", item.name, path.display());
-        let mut out = File::create(path)?;
+        let subpath = self.subpath_to_module(&item.name);
+        debug!("Render module `{}` into `{}`", item.name, subpath);
+        let mut out = vec![];
 
-        self.render_header(&mut out)?;
-        writeln!(out, "<body>")?;
-        write!(out, "<section id=\"main\" class=\"content\">")?;
         writeln!(
             out,
             "<h1 class=\"fqn\">Module <a class=\"module\">{}</a></h1>",
@@ -164,23 +339,38 @@ impl<'a> Renderer<'a> {
         self.render_doc(&item.doc, &mut out)?;
         writeln!(out, "</div>")?;
 
-        self.render_contents(&item.content, &mut out)?;
+        self.render_contents(&item.content, &subpath, &mut out)?;
+        self.render_source(&item.source, &subpath, &mut out)?;
 
-        writeln!(out, "</section>")?;
-        writeln!(out, "</body>")?;
-        writeln!(out, "</html>")?;
+        self.write_page(&subpath, out)
+    }
 
-        Ok(())
+    fn render_interface(&mut self, item: &InterfaceItem) -> Result<()> {
+        let subpath = self.subpath_to_interface(&item.name);
+        debug!("Render interface `{}` into `{}`", item.name, subpath);
+        let mut out = vec![];
+
+        writeln!(
+            out,
+            "<h1 class=\"fqn\">Interface <a class=\"interface\">{}</a></h1>",
+            item.name
+        )?;
+
+        writeln!(out, "<div class=\"docblock\">")?;
+        self.render_doc(&item.doc, &mut out)?;
+        writeln!(out, "</div>")?;
+
+        self.render_contents(&item.content, &subpath, &mut out)?;
+        self.render_source(&item.source, &subpath, &mut out)?;
+
+        self.write_page(&subpath, out)
     }
 
     fn render_type(&mut self, item: &TypeItem) -> Result<()> {
-        let path = self.path_to_type(&item.name);
-        debug!("Render type `{}` into `{}`", item.name, path.display());
-        let mut out = File::create(path)?;
+        let subpath = self.subpath_to_type(&item.name);
+        debug!("Render type `{}` into `{}`", item.name, subpath);
+        let mut out = vec![];
 
-        self.render_header(&mut out)?;
-        writeln!(out, "<body>")?;
-        write!(out, "<section id=\"main\" class=\"content\">")?;
         writeln!(
             out,
             "<h1 class=\"fqn\">Typedef <a class=\"type\">{}</a></h1>",
@@ -189,17 +379,56 @@ impl<'a> Renderer<'a> {
 
         writeln!(out, "<pre>typedef {} {};</pre>", item.ty, item.name)?;
         self.render_doc(&item.doc, &mut out)?;
+        self.render_source(&item.source, &subpath, &mut out)?;
 
-        writeln!(out, "</section>")?;
-        writeln!(out, "</body>")?;
-        writeln!(out, "</html>")?;
+        self.write_page(&subpath, out)
+    }
 
+    /// Render an in-page index of the parameters, types, functions, tasks, and signals in a
+    /// context.
+    fn render_index_of_contents(
+        &mut self,
+        cx: &Context,
+        subpath: &str,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let members: Vec<(&str, String)> = cx
+            .params
+            .iter()
+            .map(|i| (i.name.as_str(), i.html_id()))
+            .chain(cx.types.iter().map(|i| (i.name.as_str(), i.html_id())))
+            .chain(cx.functions.iter().map(|i| (i.name.as_str(), i.html_id())))
+            .chain(cx.tasks.iter().map(|i| (i.name.as_str(), i.html_id())))
+            .chain(cx.vars.iter().map(|i| (i.name.as_str(), i.html_id())))
+            .collect();
+        if members.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Index</a></h2>",
+            self.anchor_id(subpath, "index")
+        )?;
+        writeln!(out, "<ul class=\"index\">")?;
+        for (name, id) in members {
+            writeln!(
+                out,
+                "<li><a href=\"#{}\">{}</a></li>",
+                self.anchor_id(subpath, &id),
+                name
+            )?;
+        }
+        writeln!(out, "</ul>")?;
         Ok(())
     }
 
-    fn render_contents(&mut self, cx: &Context, out: &mut impl Write) -> Result<()> {
+    fn render_contents(&mut self, cx: &Context, subpath: &str, out: &mut impl Write) -> Result<()> {
         if !cx.packages.is_empty() {
-            writeln!(out, "<h2 id=\"packages\">Packages</h2>")?;
+            writeln!(
+                out,
+                "<h2 id=\"{}\">Packages</h2>",
+                self.anchor_id(subpath, "packages")
+            )?;
             writeln!(out, "<table>")?;
             for i in &cx.packages {
                 write!(
@@ -216,7 +445,11 @@ impl<'a> Renderer<'a> {
             writeln!(out, "</table>")?;
         }
         if !cx.modules.is_empty() {
-            writeln!(out, "<h2 id=\"modules\">Modules</h2>")?;
+            writeln!(
+                out,
+                "<h2 id=\"{}\">Modules</h2>",
+                self.anchor_id(subpath, "modules")
+            )?;
             writeln!(out, "<table>")?;
             for i in &cx.modules {
                 write!(
@@ -232,15 +465,46 @@ impl<'a> Renderer<'a> {
             }
             writeln!(out, "</table>")?;
         }
+        if !cx.interfaces.is_empty() {
+            writeln!(
+                out,
+                "<h2 id=\"{}\">Interfaces</h2>",
+                self.anchor_id(subpath, "interfaces")
+            )?;
+            writeln!(out, "<table>")?;
+            for i in &cx.interfaces {
+                write!(
+                    out,
+                    "<tr><td><a class=\"interface\" href=\"{}\">{}</a></td><td>",
+                    self.subpath_to_interface(&i.name),
+                    i.name
+                )?;
+                self.render_headline_doc(&i.doc, out)?;
+                write!(out, "</td></tr>")?;
+                self.render_interface(i)
+                    .with_context(|| format!("Failed ro render interface `{}`", i.name))?;
+            }
+            writeln!(out, "</table>")?;
+        }
         if !cx.params.is_empty() {
-            writeln!(out, "<h2 id=\"parameters\" class=\"section-header\"><a href=\"#parameters\">Parameters</a></h2>")?;
+            writeln!(
+                out,
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Parameters</a></h2>",
+                self.anchor_id(subpath, "parameters")
+            )?;
             for i in &cx.params {
+                let default = i
+                    .default
+                    .as_ref()
+                    .map(|x| format!(" = {}", crate::xml_escape(x)))
+                    .unwrap_or_default();
                 write!(
                     out,
-                    "<h3 id=\"{2}\" class=\"impl\"><code class=\"in-band\"><a href=\"#{2}\">{0}</a><span class=\"type-annotation\">: {1}</span></code></h3>",
+                    "<h3 id=\"{2}\" class=\"impl\"><code class=\"in-band\"><a href=\"#{2}\">{0}</a><span class=\"type-annotation\">: {1}{3}</span></code></h3>",
                     i.name,
-                    i.ty,
-                    i.html_id(),
+                    self.link_types(&i.ty),
+                    self.anchor_id(subpath, &i.html_id()),
+                    default,
                 )?;
                 writeln!(out, "<div class=\"docblock\">")?;
                 self.render_doc(&i.doc, out)?;
@@ -250,15 +514,35 @@ impl<'a> Renderer<'a> {
         if !cx.ports.is_empty() {
             writeln!(
                 out,
-                "<h2 id=\"ports\" class=\"section-header\"><a href=\"#ports\">Ports</a></h2>"
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Ports</a></h2>",
+                self.anchor_id(subpath, "ports")
             )?;
             for i in &cx.ports {
                 write!(
                     out,
                     "<h3 id=\"{2}\" class=\"impl\"><code class=\"in-band\"><a href=\"#{2}\">{0}</a><span class=\"type-annotation\">: {1}</span></code></h3>",
                     i.name,
-                    i.ty,
-                    i.html_id(),
+                    self.link_types(&i.ty),
+                    self.anchor_id(subpath, &i.html_id()),
+                )?;
+                writeln!(out, "<div class=\"docblock\">")?;
+                self.render_doc(&i.doc, out)?;
+                write!(out, "</div>")?;
+            }
+        }
+        if !cx.modports.is_empty() {
+            writeln!(
+                out,
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Modports</a></h2>",
+                self.anchor_id(subpath, "modports")
+            )?;
+            for i in &cx.modports {
+                write!(
+                    out,
+                    "<h3 id=\"{2}\" class=\"impl\"><code class=\"in-band\"><a href=\"#{2}\">{0}</a><span class=\"type-annotation\"> {1}</span></code></h3>",
+                    i.name,
+                    i.ports,
+                    self.anchor_id(subpath, &i.html_id()),
                 )?;
                 writeln!(out, "<div class=\"docblock\">")?;
                 self.render_doc(&i.doc, out)?;
@@ -268,13 +552,15 @@ impl<'a> Renderer<'a> {
         if !cx.types.is_empty() {
             writeln!(
                 out,
-                "<h2 id=\"types\" class=\"section-header\"><a href=\"#types\">Types<a></h2>"
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Types<a></h2>",
+                self.anchor_id(subpath, "types")
             )?;
             writeln!(out, "<table>")?;
             for i in &cx.types {
                 write!(
                     out,
-                    "<tr><td><a class=\"type\" href=\"{}\">{}</a></td><td>",
+                    "<tr id=\"{}\"><td><a class=\"type\" href=\"{}\">{}</a></td><td>",
+                    self.anchor_id(subpath, &i.html_id()),
                     self.subpath_to_type(&i.name),
                     i.name
                 )?;
@@ -285,18 +571,62 @@ impl<'a> Renderer<'a> {
             }
             writeln!(out, "</table>")?;
         }
+        if !cx.functions.is_empty() {
+            writeln!(
+                out,
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Functions</a></h2>",
+                self.anchor_id(subpath, "functions")
+            )?;
+            for i in &cx.functions {
+                let ty = match i.ty.is_empty() {
+                    true => String::new(),
+                    false => format!(": {}", crate::xml_escape(&i.ty)),
+                };
+                write!(
+                    out,
+                    "<h3 id=\"{2}\" class=\"impl\"><code class=\"in-band\"><a href=\"#{2}\">{0}</a>({1})<span class=\"type-annotation\">{3}</span></code></h3>",
+                    i.name,
+                    crate::xml_escape(&i.args),
+                    self.anchor_id(subpath, &i.html_id()),
+                    ty,
+                )?;
+                writeln!(out, "<div class=\"docblock\">")?;
+                self.render_doc(&i.doc, out)?;
+                write!(out, "</div>")?;
+            }
+        }
+        if !cx.tasks.is_empty() {
+            writeln!(
+                out,
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Tasks</a></h2>",
+                self.anchor_id(subpath, "tasks")
+            )?;
+            for i in &cx.tasks {
+                write!(
+                    out,
+                    "<h3 id=\"{2}\" class=\"impl\"><code class=\"in-band\"><a href=\"#{2}\">{0}</a>({1})</code></h3>",
+                    i.name,
+                    crate::xml_escape(&i.args),
+                    self.anchor_id(subpath, &i.html_id()),
+                )?;
+                writeln!(out, "<div class=\"docblock\">")?;
+                self.render_doc(&i.doc, out)?;
+                write!(out, "</div>")?;
+            }
+        }
         if !cx.vars.is_empty() {
             writeln!(
                 out,
-                "<h2 id=\"signals\" class=\"section-header\"><a href=\"#signals\">Signals</a></h2>"
+                "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Signals</a></h2>",
+                self.anchor_id(subpath, "signals")
             )?;
             for i in &cx.vars {
                 write!(
                     out,
                     "<h3 id=\"{2}\" class=\"impl\"><code class=\"in-band\"><a href=\"#{2}\">{0}</a><span class=\"type-annotation\">: {1}</span></code></h3>",
                     i.name,
-                    i.ty,
-                    i.html_id(),
+                    self.link_types(&i.ty),
+                    self.anchor_id(subpath, &i.html_id()),
                 )?;
                 writeln!(out, "<div class=\"docblock\">")?;
                 self.render_doc(&i.doc, out)?;
@@ -306,9 +636,93 @@ impl<'a> Renderer<'a> {
         Ok(())
     }
 
-    /// Render the headline markdown documentation.
+    /// Escape a type, linking the names of documented types to their page. Scoped names such as
+    /// `pkg::word_t` are linked if the unqualified name is documented.
+    fn link_types(&self, ty: &str) -> String {
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+        let mut linked = String::new();
+        let mut rest = ty;
+        while let Some(start) = rest.find(is_ident) {
+            linked.push_str(&crate::xml_escape(&rest[..start]));
+            rest = &rest[start..];
+            let mut len = 0;
+            loop {
+                len += rest[len..]
+                    .find(|c| !is_ident(c))
+                    .unwrap_or(rest.len() - len);
+                match rest[len..].strip_prefix("::") {
+                    Some(x) if x.starts_with(is_ident) => len += 2,
+                    _ => break,
+                }
+            }
+            let name = &rest[..len];
+            let unqualified = name.rsplit("::").next().unwrap_or(name);
+            if self.types.contains(unqualified) {
+                linked.push_str(&format!(
+                    "<a class=\"type\" href=\"{}\">{}</a>",
+                    self.subpath_to_type(unqualified),
+                    name
+                ));
+            } else {
+                linked.push_str(name);
+            }
+            rest = &rest[len..];
+        }
+        linked.push_str(&crate::xml_escape(rest));
+        linked
+    }
+
+    /// Render the source of a declaration, if requested, highlighting keywords and comments.
+    fn render_source(&mut self, source: &str, subpath: &str, out: &mut impl Write) -> Result<()> {
+        if !self.source_snippets {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "<h2 id=\"{0}\" class=\"section-header\"><a href=\"#{0}\">Source</a></h2>",
+            self.anchor_id(subpath, "source")
+        )?;
+        write!(out, "<pre class=\"rust\">")?;
+        for (i, line) in source.lines().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            let (code, comment) = line.split_at(line.find("//").unwrap_or(line.len()));
+            let mut word = String::new();
+            for c in code.chars().chain(std::iter::once('\n')) {
+                if c.is_alphanumeric() || c == '_' || c == '$' {
+                    word.push(c);
+                    continue;
+                }
+                if SV_KEYWORDS.contains(&word.as_str()) {
+                    write!(out, "<span class=\"kw\">{}</span>", word)?;
+                } else {
+                    write!(out, "{}", word)?;
+                }
+                word.clear();
+                if c != '\n' {
+                    write!(out, "{}", crate::xml_escape(&c.to_string()))?;
+                }
+            }
+            if !comment.is_empty() {
+                write!(
+                    out,
+                    "<span class=\"comment\">{}</span>",
+                    crate::xml_escape(comment)
+                )?;
+            }
+        }
+        writeln!(out, "</pre>")?;
+        Ok(())
+    }
+
+    /// Render the headline markdown documentation, which is the first non-empty line.
     fn render_headline_doc(&mut self, doc: &str, out: &mut impl Write) -> Result<()> {
-        let slice = doc.lines().next().unwrap_or("");
+        let slice = doc
+            .lines()
+            .map(str::trim)
+            .find(|x| !x.is_empty())
+            .unwrap_or("");
         let parser = pulldown_cmark::Parser::new_ext(slice, pulldown_cmark::Options::all());
         pulldown_cmark::html::write_html(out, parser)?;
         Ok(())
@@ -321,31 +735,98 @@ impl<'a> Renderer<'a> {
         Ok(())
     }
 
-    fn subpath_to_package(&self, name: &str) -> String {
-        format!("package.{}.html", name)
+    /// The id of an anchor within the page at `subpath`. On a single page, the ids are prefixed
+    /// with the id of the section of the page, since all pages use the same ids for their headings
+    /// and members.
+    fn anchor_id(&self, subpath: &str, id: &str) -> String {
+        match self.single_page {
+            Some(_) => format!("{}.{}", subpath.trim_start_matches('#'), id),
+            None => id.to_string(),
+        }
     }
 
-    fn subpath_to_module(&self, name: &str) -> String {
-        format!("module.{}.html", name)
+    fn subpath_to_index(&self) -> String {
+        match self.single_page {
+            Some(_) => String::from("#main"),
+            None => String::from("index.html"),
+        }
     }
 
-    fn subpath_to_type(&self, name: &str) -> String {
-        format!("type.{}.html", name)
+    fn subpath_to_all_items(&self) -> String {
+        match self.single_page {
+            Some(_) => String::from("#all"),
+            None => String::from("all.html"),
+        }
     }
 
-    fn path_to_package(&self, name: &str) -> PathBuf {
-        self.dir.join(self.subpath_to_package(name))
+    /// The link to the page of an item, which is an anchor on a single page.
+    fn subpath_to_item(&self, kind: &str, name: &str) -> String {
+        match self.single_page {
+            Some(_) => format!("#{}-{}", kind, name),
+            None => format!("{}.{}.html", kind, name),
+        }
     }
 
-    fn path_to_module(&self, name: &str) -> PathBuf {
-        self.dir.join(self.subpath_to_module(name))
+    fn subpath_to_package(&self, name: &str) -> String {
+        self.subpath_to_item("package", name)
     }
 
-    fn path_to_type(&self, name: &str) -> PathBuf {
-        self.dir.join(self.subpath_to_type(name))
+    fn subpath_to_module(&self, name: &str) -> String {
+        self.subpath_to_item("module", name)
+    }
+
+    fn subpath_to_interface(&self, name: &str) -> String {
+        self.subpath_to_item("interface", name)
+    }
+
+    fn subpath_to_type(&self, name: &str) -> String {
+        self.subpath_to_item("type", name)
     }
 }
 
+/// Keywords highlighted in source snippets.
+const SV_KEYWORDS: &[&str] = &[
+    "always_comb",
+    "always_ff",
+    "always_latch",
+    "assign",
+    "begin",
+    "bit",
+    "case",
+    "else",
+    "end",
+    "endcase",
+    "endfunction",
+    "endgenerate",
+    "endinterface",
+    "endmodule",
+    "endpackage",
+    "enum",
+    "for",
+    "function",
+    "generate",
+    "if",
+    "import",
+    "inout",
+    "input",
+    "int",
+    "interface",
+    "localparam",
+    "logic",
+    "modport",
+    "module",
+    "output",
+    "package",
+    "packed",
+    "parameter",
+    "signed",
+    "struct",
+    "typedef",
+    "union",
+    "unsigned",
+    "wire",
+];
+
 /// HTML identifier (value of `id` field).
 trait Id {
     fn html_id(&self) -> String;
@@ -363,6 +844,24 @@ impl Id for PortItem {
     }
 }
 
+impl Id for ModportItem {
+    fn html_id(&self) -> String {
+        format!("modport.{}", self.name)
+    }
+}
+
+impl Id for FunctionItem {
+    fn html_id(&self) -> String {
+        format!("function.{}", self.name)
+    }
+}
+
+impl Id for TaskItem {
+    fn html_id(&self) -> String {
+        format!("task.{}", self.name)
+    }
+}
+
 impl Id for TypeItem {
     fn html_id(&self) -> String {
         format!("type.{}", self.name)
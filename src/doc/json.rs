@@ -0,0 +1,32 @@
+//! JSON documentation rendering
+
+use super::*;
+use anyhow::{Context as _, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// A JSON renderer, writing the documentation items into a single file.
+pub struct JsonRenderer<'a> {
+    path: &'a Path,
+}
+
+impl<'a> JsonRenderer<'a> {
+    /// Create a new JSON renderer.
+    pub fn new(path: &'a Path) -> Self {
+        Self { path }
+    }
+
+    /// Render some documentation.
+    pub fn render(&mut self, doc: &Doc) -> Result<()> {
+        debug!("Render documentation into `{}`", self.path.display());
+        let file = File::create(self.path)
+            .with_context(|| format!("Failed to create `{}`", self.path.display()))?;
+        let mut out = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut out, &doc.data)?;
+        writeln!(out)?;
+        Ok(())
+    }
+}
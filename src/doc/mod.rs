@@ -4,11 +4,14 @@
 //! generation.
 
 use crate::ParsedFile;
-use sv_parser::{self as sv, RefNode};
+use serde::Serialize;
+use sv_parser::{self as sv, unwrap_node, RefNode};
 
 mod html;
+mod json;
 mod raw;
 pub use html::*;
+pub use json::*;
 pub use raw::*;
 
 /// Documentation generated for a syntax tree.
@@ -17,6 +20,8 @@ pub struct Doc<'a> {
     pub raw: Vec<(&'a ParsedFile, RawDoc<'a>)>,
     /// The documentation items.
     pub data: Context,
+    /// Files which could not be parsed and are missing from the documentation.
+    pub omitted: Vec<String>,
 }
 
 impl<'a> Doc<'a> {
@@ -36,16 +41,24 @@ impl<'a> Doc<'a> {
         debug!("{:#?}", data);
 
         // Package up.
-        Self { raw, data }
+        Self {
+            raw,
+            data,
+            omitted: vec![],
+        }
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Serialize)]
 pub struct Context {
     params: Vec<ParamItem>,
     ports: Vec<PortItem>,
     types: Vec<TypeItem>,
     modules: Vec<ModuleItem>,
+    interfaces: Vec<InterfaceItem>,
+    modports: Vec<ModportItem>,
+    functions: Vec<FunctionItem>,
+    tasks: Vec<TaskItem>,
     packages: Vec<PackageItem>,
     vars: Vec<VarItem>,
 }
@@ -57,25 +70,79 @@ impl Context {
             None => return,
         };
         match node {
-            RefNode::PackageDeclaration(decl) => {
-                self.packages
-                    .push(PackageItem::from(raw, scope, &(decl.nodes.3).nodes.0))
-            }
-            RefNode::ModuleDeclaration(decl) => self.modules.push(match decl {
-                sv::ModuleDeclaration::Nonansi(decl) => {
-                    ModuleItem::from(raw, scope, &(decl.nodes.0).nodes.3.nodes.0)
+            RefNode::PackageDeclaration(decl) => push_unique(
+                &mut self.packages,
+                PackageItem::from(
+                    raw,
+                    scope,
+                    &(decl.nodes.3).nodes.0,
+                    parse_source(raw, *decl),
+                ),
+            ),
+            RefNode::ModuleDeclaration(decl) => push_unique(
+                &mut self.modules,
+                match decl {
+                    sv::ModuleDeclaration::Nonansi(decl) => ModuleItem::from(
+                        raw,
+                        scope,
+                        &(decl.nodes.0).nodes.3.nodes.0,
+                        parse_source(raw, &**decl),
+                    ),
+                    sv::ModuleDeclaration::Ansi(decl) => ModuleItem::from(
+                        raw,
+                        scope,
+                        &(decl.nodes.0).nodes.3.nodes.0,
+                        parse_source(raw, &**decl),
+                    ),
+                    _ => return,
+                },
+            ),
+            RefNode::InterfaceDeclaration(decl) => push_unique(
+                &mut self.interfaces,
+                match decl {
+                    sv::InterfaceDeclaration::Nonansi(decl) => InterfaceItem::from(
+                        raw,
+                        scope,
+                        &(decl.nodes.0).nodes.3.nodes.0,
+                        parse_source(raw, &**decl),
+                    ),
+                    sv::InterfaceDeclaration::Ansi(decl) => InterfaceItem::from(
+                        raw,
+                        scope,
+                        &(decl.nodes.0).nodes.3.nodes.0,
+                        parse_source(raw, &**decl),
+                    ),
+                    _ => return,
+                },
+            ),
+            RefNode::ModportDeclaration(decl) => {
+                for item in decl.nodes.1.contents() {
+                    self.modports.push(ModportItem::from(raw, scope, item));
                 }
-                sv::ModuleDeclaration::Ansi(decl) => {
-                    ModuleItem::from(raw, scope, &(decl.nodes.0).nodes.3.nodes.0)
+            }
+            RefNode::FunctionDeclaration(decl) => {
+                if let Some(item) = FunctionItem::from(raw, scope, decl) {
+                    self.functions.push(item);
                 }
-                _ => return,
-            }),
-            RefNode::TypeDeclaration(decl) => self.types.push(match decl {
-                sv::TypeDeclaration::DataType(decl) => {
-                    TypeItem::from(raw, scope, &(decl.nodes.2).nodes.0, &decl.nodes.1)
+            }
+            RefNode::TaskDeclaration(decl) => {
+                if let Some(item) = TaskItem::from(raw, scope, decl) {
+                    self.tasks.push(item);
                 }
-                _ => return,
-            }),
+            }
+            RefNode::TypeDeclaration(decl) => push_unique(
+                &mut self.types,
+                match decl {
+                    sv::TypeDeclaration::DataType(decl) => TypeItem::from(
+                        raw,
+                        scope,
+                        &(decl.nodes.2).nodes.0,
+                        &decl.nodes.1,
+                        parse_source(raw, &**decl),
+                    ),
+                    _ => return,
+                },
+            ),
             RefNode::NetDeclaration(decl) => match decl {
                 sv::NetDeclaration::NetTypeIdentifier(decl) => {
                     for decl_assign in decl.nodes.2.nodes.0.contents() {
@@ -156,8 +223,55 @@ impl Context {
     }
 }
 
+/// A declaration which is rendered into a page of its own.
+trait Declaration {
+    fn name(&self) -> &str;
+    fn source(&self) -> &str;
+    fn doc_mut(&mut self) -> &mut String;
+}
+
+macro_rules! impl_declaration {
+    ($($ty:ty),*) => {
+        $(impl Declaration for $ty {
+            fn name(&self) -> &str {
+                &self.name
+            }
+            fn source(&self) -> &str {
+                &self.source
+            }
+            fn doc_mut(&mut self) -> &mut String {
+                &mut self.doc
+            }
+        })*
+    };
+}
+
+impl_declaration!(PackageItem, ModuleItem, InterfaceItem, TypeItem);
+
+/// Add a declaration, unless one of the same name exists already, e.g. in conditionally compiled
+/// alternatives of a file. The first declaration is kept, but adopts the documentation of later
+/// ones if it has none itself.
+fn push_unique<T: Declaration>(items: &mut Vec<T>, mut item: T) {
+    let existing = match items.iter_mut().find(|x| x.name() == item.name()) {
+        Some(x) => x,
+        None => {
+            items.push(item);
+            return;
+        }
+    };
+    if existing.source() != item.source() {
+        warn!(
+            "Conflicting declarations of `{}`, documenting the first one",
+            item.name()
+        );
+    }
+    if existing.doc_mut().is_empty() {
+        std::mem::swap(existing.doc_mut(), item.doc_mut());
+    }
+}
+
 /// Documentation for a package.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct PackageItem {
     /// Documentation text.
     pub doc: String,
@@ -165,22 +279,25 @@ pub struct PackageItem {
     pub name: String,
     /// The package contents.
     pub content: Context,
+    /// The package declaration source.
+    pub source: String,
 }
 
 impl PackageItem {
-    fn from(raw: &RawDoc, scope: &Scope, name: &sv::Identifier) -> Self {
+    fn from(raw: &RawDoc, scope: &Scope, name: &sv::Identifier, source: String) -> Self {
         let mut content = Context::default();
         content.analyze_scopes(raw, &scope.children);
         Self {
             doc: parse_docs(raw, &scope.comments),
             name: parse_ident(raw, name),
             content,
+            source,
         }
     }
 }
 
 /// Documentation for a module.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct ModuleItem {
     /// Documentation text.
     pub doc: String,
@@ -188,22 +305,130 @@ pub struct ModuleItem {
     pub name: String,
     /// The module contents.
     pub content: Context,
+    /// The module declaration source.
+    pub source: String,
 }
 
 impl ModuleItem {
-    fn from(raw: &RawDoc, scope: &Scope, name: &sv::Identifier) -> Self {
+    fn from(raw: &RawDoc, scope: &Scope, name: &sv::Identifier, source: String) -> Self {
         let mut content = Context::default();
         content.analyze_scopes(raw, &scope.children);
         Self {
             doc: parse_docs(raw, &scope.comments),
             name: parse_ident(raw, name),
             content,
+            source,
         }
     }
 }
 
+/// Documentation for an interface.
+#[derive(Debug, Serialize)]
+pub struct InterfaceItem {
+    /// Documentation text.
+    pub doc: String,
+    /// Interface name.
+    pub name: String,
+    /// The interface contents.
+    pub content: Context,
+    /// The interface declaration source.
+    pub source: String,
+}
+
+impl InterfaceItem {
+    fn from(raw: &RawDoc, scope: &Scope, name: &sv::Identifier, source: String) -> Self {
+        let mut content = Context::default();
+        content.analyze_scopes(raw, &scope.children);
+        Self {
+            doc: parse_docs(raw, &scope.comments),
+            name: parse_ident(raw, name),
+            content,
+            source,
+        }
+    }
+}
+
+/// Documentation for a modport.
+#[derive(Debug, Serialize)]
+pub struct ModportItem {
+    /// Documentation text.
+    pub doc: String,
+    /// Modport name.
+    pub name: String,
+    /// Modport ports, including the parentheses.
+    pub ports: String,
+}
+
+impl ModportItem {
+    fn from(raw: &RawDoc, scope: &Scope, item: &sv::ModportItem) -> Self {
+        let name = parse_ident(raw, &item.nodes.0.nodes.0);
+        let text = raw.ast.get_str(item).unwrap_or_default().trim();
+        Self {
+            doc: parse_docs(raw, &scope.comments),
+            ports: text.strip_prefix(&name).unwrap_or(text).trim().to_string(),
+            name,
+        }
+    }
+}
+
+/// Documentation for a function.
+#[derive(Debug, Serialize)]
+pub struct FunctionItem {
+    /// Documentation text.
+    pub doc: String,
+    /// Function name.
+    pub name: String,
+    /// Return type, empty if implicit.
+    pub ty: String,
+    /// Arguments, empty if declared in the function body.
+    pub args: String,
+}
+
+impl FunctionItem {
+    fn from(raw: &RawDoc, scope: &Scope, decl: &sv::FunctionDeclaration) -> Option<Self> {
+        let name = match unwrap_node!(decl, FunctionIdentifier) {
+            Some(RefNode::FunctionIdentifier(x)) => parse_ident(raw, &x.nodes.0),
+            _ => return None,
+        };
+        Some(Self {
+            doc: parse_docs(raw, &scope.comments),
+            name,
+            ty: match unwrap_node!(decl, FunctionDataTypeOrImplicit) {
+                Some(RefNode::FunctionDataTypeOrImplicit(x)) => parse_source(raw, x),
+                _ => String::new(),
+            },
+            args: parse_args(raw, unwrap_node!(decl, TfPortList)),
+        })
+    }
+}
+
+/// Documentation for a task.
+#[derive(Debug, Serialize)]
+pub struct TaskItem {
+    /// Documentation text.
+    pub doc: String,
+    /// Task name.
+    pub name: String,
+    /// Arguments, empty if declared in the task body.
+    pub args: String,
+}
+
+impl TaskItem {
+    fn from(raw: &RawDoc, scope: &Scope, decl: &sv::TaskDeclaration) -> Option<Self> {
+        let name = match unwrap_node!(decl, TaskIdentifier) {
+            Some(RefNode::TaskIdentifier(x)) => parse_ident(raw, &x.nodes.0),
+            _ => return None,
+        };
+        Some(Self {
+            doc: parse_docs(raw, &scope.comments),
+            name,
+            args: parse_args(raw, unwrap_node!(decl, TfPortList)),
+        })
+    }
+}
+
 /// Documentation for a parameter.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct ParamItem {
     /// Documentation text.
     pub doc: String,
@@ -211,6 +436,8 @@ pub struct ParamItem {
     pub name: String,
     /// Parameter type.
     pub ty: String,
+    /// Default value, or default type of type parameters.
+    pub default: Option<String>,
 }
 
 impl ParamItem {
@@ -224,6 +451,12 @@ impl ParamItem {
             doc: parse_docs(raw, &scope.comments),
             name: parse_ident(raw, &assign.nodes.0.nodes.0),
             ty: raw.ast.get_str(ty).unwrap_or_default().trim().to_string(),
+            default: assign
+                .nodes
+                .2
+                .as_ref()
+                .and_then(|(_, expr)| raw.ast.get_str(expr))
+                .map(|x| x.trim().to_string()),
         }
     }
 
@@ -232,12 +465,18 @@ impl ParamItem {
             doc: parse_docs(raw, &scope.comments),
             name: parse_ident(raw, &assign.nodes.0.nodes.0),
             ty: String::from("type"),
+            default: assign
+                .nodes
+                .1
+                .as_ref()
+                .and_then(|(_, ty)| raw.ast.get_str(ty))
+                .map(|x| x.trim().to_string()),
         }
     }
 }
 
 /// Documentation for a port.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct PortItem {
     /// Documentation text.
     pub doc: String,
@@ -263,7 +502,7 @@ impl PortItem {
 }
 
 /// Documentation for a type.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct TypeItem {
     /// Documentation text.
     pub doc: String,
@@ -271,20 +510,29 @@ pub struct TypeItem {
     pub name: String,
     /// Inner type.
     pub ty: String,
+    /// The type declaration source.
+    pub source: String,
 }
 
 impl TypeItem {
-    fn from(raw: &RawDoc, scope: &Scope, name: &sv::Identifier, ty: &sv::DataType) -> Self {
+    fn from(
+        raw: &RawDoc,
+        scope: &Scope,
+        name: &sv::Identifier,
+        ty: &sv::DataType,
+        source: String,
+    ) -> Self {
         Self {
             doc: parse_docs(raw, &scope.comments),
             name: parse_ident(raw, name),
             ty: raw.ast.get_str(ty).unwrap().trim().to_string(),
+            source,
         }
     }
 }
 
 /// Documentation for a variable.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct VarItem {
     /// Documentation text.
     pub doc: String,
@@ -315,11 +563,22 @@ impl VarItem {
 }
 
 fn parse_docs(_raw: &RawDoc, comments: &[&str]) -> String {
-    // Compute the common number of leading spaces in all non-empty lines.
+    // Drop leading and trailing blank lines, such that the first line is the headline.
+    let is_blank = |s: &&str| s.trim().is_empty();
+    let start = comments
+        .iter()
+        .position(|s| !is_blank(s))
+        .unwrap_or(comments.len());
+    let end = comments
+        .iter()
+        .rposition(|s| !is_blank(s))
+        .map_or(start, |x| x + 1);
+    let comments = &comments[start..end];
+
+    // Compute the common number of leading spaces in all non-blank lines.
     let common_spaces = comments
         .iter()
-        .copied()
-        .filter(|s| !s.is_empty())
+        .filter(|s| !is_blank(s))
         .map(|s| s.chars().take_while(|c| c.is_whitespace()).count())
         .min()
         .unwrap_or(0);
@@ -337,6 +596,17 @@ fn parse_docs(_raw: &RawDoc, comments: &[&str]) -> String {
     result
 }
 
+fn parse_source<'a>(raw: &RawDoc, decl: impl Into<sv::RefNodes<'a>>) -> String {
+    raw.ast.get_str(decl).unwrap_or_default().trim().to_string()
+}
+
+fn parse_args(raw: &RawDoc, ports: Option<RefNode>) -> String {
+    match ports {
+        Some(RefNode::TfPortList(x)) => parse_source(raw, x),
+        _ => String::new(),
+    }
+}
+
 fn parse_ident(raw: &RawDoc, ident: &sv::Identifier) -> String {
     raw.ast
         .get_str(match ident {
@@ -55,6 +55,10 @@ impl<'a> RawDoc<'a> {
                     | RefNode::LocalParameterDeclaration(..)
                     | RefNode::AnsiPortDeclaration(..)
                     | RefNode::ModuleDeclaration(..)
+                    | RefNode::InterfaceDeclaration(..)
+                    | RefNode::ModportDeclaration(..)
+                    | RefNode::FunctionDeclaration(..)
+                    | RefNode::TaskDeclaration(..)
                     | RefNode::PackageDeclaration(..) => {
                         last_comment = LastComment::None;
                         stack.push(Scope::new(node.clone(), std::mem::take(&mut comments)));
@@ -73,7 +77,11 @@ impl<'a> RawDoc<'a> {
                     | RefNode::ModuleItem(..)
                     | RefNode::ParameterPortDeclaration(..)
                     | RefNode::ModuleCommonItem(..)
-                    | RefNode::ModuleOrGenerateItemModuleItem(..) => (),
+                    | RefNode::ModuleOrGenerateItemModuleItem(..)
+                    | RefNode::InterfaceItem(..)
+                    | RefNode::NonPortInterfaceItem(..)
+                    | RefNode::InterfaceOrGenerateItem(..)
+                    | RefNode::InterfaceOrGenerateItemModule(..) => (),
                     _ => {
                         last_comment = LastComment::None;
                         if !comments.is_empty() {
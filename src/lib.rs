@@ -3,46 +3,181 @@
 // Copyright 2022 Michael Rogenmoser
 
 // SPDX-License-Identifier: Apache-2.0
+
+//! Pickle SystemVerilog sources into a single file.
+//!
+//! Besides the `morty` binary, the crate can be used as a library: parse the sources with
+//! [`build_syntax_tree`], register them in a [`Pickle`] with [`Pickle::build_graph`], optionally
+//! prune the graph to a top module with [`Pickle::prune_graph`], and obtain the renamed sources
+//! with [`Pickle::get_pickle_string`]. [`do_pickle`] bundles these steps for the command line.
+
 #![recursion_limit = "256"]
 
 #[macro_use]
 extern crate log;
 
 use anyhow::{anyhow, Context as _, Error, Result};
-use petgraph::algo::dijkstra;
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::GzDecoder;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use petgraph::algo::{dijkstra, is_cyclic_directed, tarjan_scc, toposort};
 use petgraph::graph::{Graph, NodeIndex};
+use petgraph::visit::Dfs;
+use petgraph::Direction;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use sv_parser::Error as SvParserError;
 use sv_parser::{
-    parse_sv_pp, preprocess, unwrap_node, Define, DefineText, Defines, Locate, RefNode, SyntaxTree,
+    parse_sv_pp, parse_sv_str, preprocess, unwrap_node, BindDirective, ConfigDeclaration, Define,
+    DefineText, Defines, InterfaceDeclaration, InterfacePortHeader, Locate, ModuleInstantiation,
+    PackageDeclaration, PackageImportDeclaration, RefNode, SyntaxTree,
 };
 use time::OffsetDateTime;
 
 pub mod doc;
 mod printer;
 
+/// Options of a pickle, see `do_pickle`.
+#[derive(Default)]
+pub struct PickleOptions<'a> {
+    /// Prefix added to the names of renamed declarations.
+    pub prefix: Option<&'a String>,
+    /// Suffix added to the names of renamed declarations.
+    pub suffix: Option<&'a String>,
+    /// Declarations which are not renamed.
+    pub exclude_rename: HashSet<&'a String>,
+    /// Declarations which are excluded from the pickle.
+    pub exclude: HashSet<&'a String>,
+    /// Declarations of a specific kind which are excluded from the pickle.
+    pub exclude_kinds: HashSet<(SVConstructType, String)>,
+    /// Top module, or instance path to it, below which the declarations are kept.
+    pub top_module: Option<&'a String>,
+    /// Keep the `` `define `` directives in the pickle.
+    pub keep_defines: bool,
+    /// Defines of earlier files apply to the later ones.
+    pub propagate_defines: bool,
+    /// Remove timeunits and `` `timescale `` directives.
+    pub remove_timeunits: bool,
+    /// Directory to write one pickle per top into, containing the declarations reachable from it.
+    /// Tops are all declarations nothing else depends on, including unused packages.
+    pub split_output: Option<&'a String>,
+    /// Emit a comment with the originating file in front of each declaration.
+    pub annotate_sources: bool,
+    /// Strip packages without any items from the pickled sources.
+    pub dedupe_blank_decls: bool,
+    /// Keep the first of multiple declarations with the same name instead of failing.
+    pub allow_duplicate_modules: bool,
+    /// Inline packages which are only imported by a single declaration.
+    pub flatten_single_use_packages: bool,
+    /// Timescale emitted at the top of the pickle.
+    pub timescale: Option<&'a String>,
+    /// Text prepended to the pickle, such as a license header.
+    pub banner: Option<&'a String>,
+    /// Omit the `// Compiled by morty` header line.
+    pub no_header: bool,
+    /// Omit the date from the header line, such that the pickle is reproducible.
+    pub no_timestamp: bool,
+    /// Rename instances along with the declarations.
+    pub rename_instances: bool,
+    /// Canonical paths of files excluded from the pickle.
+    pub exclude_files: HashSet<PathBuf>,
+    /// Style of the comments morty adds to the pickle.
+    pub comment_style: CommentStyle,
+    /// Fail on cyclic module dependencies.
+    pub error_on_cyclic: bool,
+    /// Fail on instantiations of undeclared modules.
+    pub error_on_undefined: bool,
+    /// Only emit the first of identical global package imports.
+    pub dedup_global_imports: bool,
+    /// File to write the pickled source files into, in emission order.
+    pub ordered_flist: Option<&'a String>,
+    /// Suppress informational messages on stderr.
+    pub quiet: bool,
+    /// Prepend a table of contents of the declarations to the pickle.
+    pub toc: bool,
+    /// Strip DPI import and export declarations.
+    pub strip_dpi: bool,
+    /// Expand `.*` port connections into named connections.
+    pub expand_dot_star: bool,
+    /// Width in columns at which the connections of an expanded `.*` are wrapped.
+    pub max_line_width: Option<usize>,
+    /// Expand implicit named port connections such as `.clk`.
+    pub expand_implicit_ports: bool,
+    /// Instance paths whose subtrees are excluded below the top module.
+    pub exclude_paths: Vec<String>,
+    /// Case to which names are normalized before applying prefix and suffix.
+    pub normalize_case: Option<NormalizeCase>,
+    /// Drop the declarations only used by excluded declarations.
+    pub exclude_prune: bool,
+    /// Order in which the files are pickled.
+    pub pickle_format: PickleFormat,
+    /// Only validate the sources and the module graph, without writing the pickle.
+    pub dry_run: bool,
+    /// Only build the module graph to query the declarations, without pruning or writing the
+    /// pickle.
+    pub graph_only: bool,
+}
+
+/// Pickle the parsed files into `out` as configured by `options`. Returns the pickle, to report
+/// on it once written.
 pub fn do_pickle<'a>(
-    prefix: Option<&'a String>,
-    suffix: Option<&'a String>,
-    exclude_rename: HashSet<&'a String>,
-    exclude: HashSet<&'a String>,
+    options: PickleOptions<'a>,
     library_bundle: LibraryBundle,
     mut syntax_trees: Vec<ParsedFile>,
     mut out: Box<dyn Write>,
-    top_module: Option<&'a String>,
-    keep_defines: bool,
-    propagate_defines: bool,
-    remove_timeunits: bool,
 ) -> Result<Pickle<'a>> {
+    let PickleOptions {
+        prefix,
+        suffix,
+        exclude_rename,
+        exclude,
+        exclude_kinds,
+        top_module,
+        keep_defines,
+        propagate_defines,
+        remove_timeunits,
+        split_output,
+        annotate_sources,
+        dedupe_blank_decls,
+        allow_duplicate_modules,
+        flatten_single_use_packages,
+        timescale,
+        banner,
+        no_header,
+        no_timestamp,
+        rename_instances,
+        exclude_files,
+        comment_style,
+        error_on_cyclic,
+        error_on_undefined,
+        dedup_global_imports,
+        ordered_flist,
+        quiet,
+        toc,
+        strip_dpi,
+        expand_dot_star,
+        max_line_width,
+        expand_implicit_ports,
+        exclude_paths,
+        normalize_case,
+        exclude_prune,
+        pickle_format,
+        dry_run,
+        graph_only,
+    } = options;
+
     let mut pickle = Pickle::new(
         // Collect renaming options.
         prefix,
@@ -51,282 +186,184 @@ pub fn do_pickle<'a>(
         exclude,
         library_bundle,
     );
+    pickle.exclude_kinds = exclude_kinds;
+    pickle.annotate_sources = annotate_sources;
+    pickle.dedupe_blank_decls = dedupe_blank_decls;
+    pickle.timescale = timescale.cloned();
+    pickle.banner = banner.cloned();
+    pickle.no_header = no_header;
+    pickle.no_timestamp = no_timestamp;
+    pickle.rename_instances = rename_instances;
+    pickle.exclude_files = exclude_files;
+    pickle.comment_style = comment_style;
+    pickle.dedup_global_imports = dedup_global_imports;
+    pickle.max_line_width = max_line_width;
+    pickle.quiet = quiet;
+    pickle.toc = toc;
+    pickle.strip_dpi = strip_dpi;
+    pickle.expand_implicit_ports = expand_implicit_ports;
+    pickle.normalize_case = normalize_case;
+    pickle.pickle_format = pickle_format;
 
-    // Gather information for pickling.
-    for pf in &syntax_trees {
-        // println!("{}", pf.ast);
-        for node in &pf.ast {
-            trace!("{:#?}", node);
-            match node {
-                // Module declarations.
-                RefNode::ModuleDeclarationAnsi(x) => {
-                    // unwrap_node! gets the nearest ModuleIdentifier from x
-                    let id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                    pickle.register_declaration(&pf.ast, id, pf.path.clone());
-                }
-                RefNode::ModuleDeclarationNonansi(x) => {
-                    let id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                    pickle.register_declaration(&pf.ast, id, pf.path.clone());
-                }
-                // Interface Declaration.
-                RefNode::InterfaceDeclaration(x) => {
-                    let id = unwrap_node!(x, InterfaceIdentifier).unwrap();
-                    pickle.register_declaration(&pf.ast, id, pf.path.clone());
-                }
-                // Package declarations.
-                RefNode::PackageDeclaration(x) => {
-                    let id = unwrap_node!(x, PackageIdentifier).unwrap();
-                    pickle.register_declaration(&pf.ast, id, pf.path.clone());
-                }
-                _ => (),
-            }
-        }
-    }
+    pickle.build_graph(&mut syntax_trees, allow_duplicate_modules)?;
 
-    let mut library_files: Vec<ParsedFile> = vec![];
-    for pf in &syntax_trees {
-        // global package import
-        let global_packages = &pf
-            .ast
-            .into_iter()
-            .filter_map(|node| {
-                if let RefNode::DescriptionPackageItem(x) = node {
-                    if let Some(package_import) = unwrap_node!(x, PackageImportDeclaration) {
-                        let (name, _loc) = get_identifier(
-                            &pf.ast,
-                            unwrap_node!(package_import, SimpleIdentifier).unwrap(),
-                        );
-                        eprintln!(
-                            "Global package import in {}:\n\t{}",
-                            &pf.path,
-                            &pf.source[Locate::try_from(x).unwrap().offset
-                                ..(Locate::try_from(x).unwrap().offset
-                                    + Locate::try_from(x).unwrap().len)]
-                        );
-                        Some(name)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+    // Queries are answered from the declarations and the module graph alone.
+    if graph_only {
+        return Ok(pickle);
+    }
 
-        for node in &pf.ast {
-            match node {
-                // Module declarations.
-                RefNode::ModuleDeclarationAnsi(x) => {
-                    // unwrap_node! gets the nearest ModuleIdentifier from x
-                    let parent_id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                    let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+    if error_on_cyclic || dry_run {
+        pickle.check_cycles()?;
+    }
 
-                    let my_ref_node: RefNode = x.into();
-                    pickle.find_and_register_instantiations(
-                        &pf.ast,
-                        my_ref_node,
-                        &parent_name,
-                        &mut library_files,
-                    );
-                    for package in global_packages {
-                        pickle.add_dependency_relation(package, &parent_name);
-                    }
-                }
-                RefNode::ModuleDeclarationNonansi(x) => {
-                    let parent_id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                    let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+    if error_on_undefined {
+        pickle.check_undefined()?;
+    }
 
-                    let my_ref_node: RefNode = x.into();
-                    pickle.find_and_register_instantiations(
-                        &pf.ast,
-                        my_ref_node,
-                        &parent_name,
-                        &mut library_files,
-                    );
-                    for package in global_packages {
-                        pickle.add_dependency_relation(package, &parent_name);
-                    }
-                }
-                // Interface Declaration.
-                RefNode::InterfaceDeclaration(x) => {
-                    let parent_id = unwrap_node!(x, InterfaceIdentifier).unwrap();
-                    let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+    // A top given as instance path is resolved to the module of the last instance.
+    let top_module = top_module.map(|x| pickle.resolve_top(x)).transpose()?;
 
-                    let my_ref_node: RefNode = x.into();
-                    pickle.find_and_register_instantiations(
-                        &pf.ast,
-                        my_ref_node,
-                        &parent_name,
-                        &mut library_files,
-                    );
-                    for package in global_packages {
-                        pickle.add_dependency_relation(package, &parent_name);
-                    }
-                }
-                // Package declarations.
-                RefNode::PackageDeclaration(x) => {
-                    let parent_id = unwrap_node!(x, PackageIdentifier).unwrap();
-                    let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+    // Files without any declaration only contribute defines, which later files may depend on when
+    // defines are propagated. Remember them before pruning, such that they are never dropped.
+    // Without a top module, such files are always emitted.
+    let define_files: HashSet<String> = match propagate_defines || top_module.is_none() {
+        true => syntax_trees
+            .iter()
+            .map(|pf| pf.path.clone())
+            .filter(|path| !pickle.module_file_map.values().any(|x| x == path))
+            .collect(),
+        false => HashSet::new(),
+    };
 
-                    let my_ref_node: RefNode = x.into();
-                    pickle.find_and_register_instantiations(
-                        &pf.ast,
-                        my_ref_node,
-                        &parent_name,
-                        &mut library_files,
-                    );
-                    for package in global_packages {
-                        pickle.add_dependency_relation(package, &parent_name);
-                    }
-                }
-                _ => (),
-            }
-        }
+    if exclude_prune {
+        pickle.exclude_prune();
     }
 
-    syntax_trees.extend(library_files);
-    write!(
-        out,
-        "// Compiled by morty-{} / {}\n\n",
-        env!("CARGO_PKG_VERSION"),
-        OffsetDateTime::now_local().unwrap_or(OffsetDateTime::now_utc())
-    )
-    .unwrap();
-
-    if let Some(top) = top_module {
-        if propagate_defines {
-            warn!(
-                "Pickle might be non-functional as some files can be excluded due to use of --top={}.\
-                \n\tThis might lead to required components being excluded. Use at your own risk!!!",
-                top
-            );
+    if let Some(top) = &top_module {
+        for path in &exclude_paths {
+            pickle.exclude_path(top, path)?;
         }
         pickle.prune_graph(top)?;
     }
 
-    let needed_files = pickle
-        .module_file_map
-        .clone()
-        .into_values()
-        .collect::<Vec<_>>();
+    // A dry run only validates the sources and the module graph.
+    if dry_run {
+        return Ok(pickle);
+    }
 
-    // Emit the pickled source files.
-    for pf in &syntax_trees {
-        if top_module.is_some() && !needed_files.contains(&pf.path) {
-            continue;
-        }
-        // For each file, start with a clean replacement table.
-        pickle.replace_table.clear();
-        // Iterate again and check for usage
-        for node in &pf.ast {
-            match node {
-                // Instantiations, end-labels.
-                RefNode::ModuleIdentifier(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_usage(&pf.ast, id);
-                }
-                // Interface identifier.
-                RefNode::InterfaceIdentifier(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_usage(&pf.ast, id);
-                }
-                // Package Qualifier (i.e., explicit package constants).
-                RefNode::ClassScope(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_usage(&pf.ast, id);
-                }
-                // Package Import.
-                RefNode::PackageIdentifier(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_usage(&pf.ast, id);
-                }
-                // Check whether we want to exclude the given module from the file sources.
-                RefNode::ModuleDeclarationAnsi(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_exclude(&pf.ast, id, Locate::try_from(x).unwrap())
-                }
-                RefNode::ModuleDeclarationNonansi(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_exclude(&pf.ast, id, Locate::try_from(x).unwrap())
-                }
-                RefNode::InterfaceDeclaration(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_exclude(&pf.ast, id, Locate::try_from(x).unwrap())
-                }
-                RefNode::PackageDeclaration(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    pickle.register_exclude(&pf.ast, id, Locate::try_from(x).unwrap())
-                }
-                RefNode::TimeunitsDeclaration(x) => {
-                    let loc = Locate::try_from(x).unwrap();
-                    if remove_timeunits {
-                        pickle
-                            .replace_table
-                            .push((loc.offset, loc.len, "".to_string()));
-                    }
-                }
-                _ => (),
-            }
-        }
+    if flatten_single_use_packages {
+        pickle.flatten_single_use_packages(&syntax_trees)?;
+    }
 
-        // Find macros to be removed
-        let mut new_replace_table = Vec::new();
+    if strip_dpi {
+        pickle.check_dpi_callers(&syntax_trees);
+    }
 
-        if !keep_defines {
-            for node in &pf.ast {
-                if let RefNode::TextMacroDefinition(x) = node {
-                    let loc = Locate::try_from(x).unwrap();
-                    new_replace_table.push((loc.offset, loc.len, "".to_string()));
-                }
-            }
-        }
+    if rename_instances {
+        pickle.check_hierarchical_references(&syntax_trees);
+    }
 
-        new_replace_table.append(&mut pickle.replace_table);
+    if expand_dot_star {
+        pickle.collect_ports(&syntax_trees);
+    }
 
-        // sort replace table
-        new_replace_table.sort_by(|a, b| a.0.cmp(&b.0));
+    if let Some(dir) = split_output {
+        pickle.get_split_pickle(
+            &syntax_trees,
+            Path::new(dir),
+            keep_defines,
+            remove_timeunits,
+        )?;
+        return Ok(pickle);
+    }
 
-        // Error on overlapping -> correct overlapping!
-        for window in new_replace_table.windows(2) {
-            if window[0].0 + window[0].1 > window[1].0 {
-                eprintln!("Offset error, please contact Michael\n{:?}", window[0]);
+    // With a top module or pruned exclusions, only emit the files declaring what remains after
+    // pruning.
+    let files: Option<HashSet<String>> = match top_module.is_some() || exclude_prune {
+        true => {
+            let mut files = pickle
+                .module_file_map
+                .values()
+                .cloned()
+                .chain(define_files)
+                .collect();
+            // Pruned files may still define macros the remaining files depend on.
+            if propagate_defines {
+                keep_macro_files(&syntax_trees, pickle.libs.encoding, &mut files)?;
             }
+            Some(files)
         }
+        false => None,
+    };
 
-        // Replace according to `replace_table`.
-        // Apply the replacements.
-        debug!("Replace Table: {:?}", new_replace_table);
-        let mut pos = 0;
-        for (offset, len, repl) in new_replace_table.iter() {
-            // Because we are partially stripping modules it can be the case that we don't need to apply some of the upcoming replacements.
-            if pos > *offset {
-                continue;
-            }
-            trace!("Replacing: {},{}, {}", offset, len, repl);
-            write!(out, "{}", &pf.source[pos..*offset]).unwrap();
-            write!(out, "{}", repl).unwrap();
-            pos = offset + len;
-        }
-        write!(out, "{}", &pf.source[pos..]).unwrap();
-        // Make sure that each file ends with a newline.
-        if !pf.source.ends_with('\n') {
-            writeln!(out).unwrap();
-        }
+    if let Some(flist) = ordered_flist {
+        write_ordered_flist(&pickle, flist, &syntax_trees, files.as_ref())?;
     }
 
+    let pickled = pickle.get_pickle_string(
+        &syntax_trees,
+        files.as_ref(),
+        keep_defines,
+        remove_timeunits,
+    )?;
+    write!(out, "{}", pickled)?;
+
     Ok(pickle)
 }
 
+/// Check that a timescale has the form `<unit>/<precision>`, e.g. `1ns/1ps`.
+pub fn check_timescale(timescale: &str) -> Result<()> {
+    let is_time_literal = |x: &str| {
+        let x = x.trim();
+        let digits = x.find(|c: char| !c.is_ascii_digit()).unwrap_or(x.len());
+        matches!(&x[..digits], "1" | "10" | "100")
+            && matches!(x[digits..].trim(), "s" | "ms" | "us" | "ns" | "ps" | "fs")
+    };
+    match timescale.split_once('/') {
+        Some((unit, precision)) if is_time_literal(unit) && is_time_literal(precision) => Ok(()),
+        _ => Err(anyhow!(
+            "Invalid timescale `{}`, expected `<unit>/<precision>` such as `1ns/1ps`",
+            timescale
+        )),
+    }
+}
+
 pub fn build_syntax_tree(
     file_list: &Vec<FileBundle>,
     strip_comments: bool,
+    keep_comments_in: &HashSet<PathBuf>,
+    keep_pragmas: &[String],
     ignore_unparseable: bool,
     propagate_defines: bool,
     force_sequential: bool,
+    parse_stack_size: usize,
+    encoding: &'static Encoding,
+    collect_conditionals: bool,
+    collect_line_marks: bool,
 ) -> Result<Vec<ParsedFile>> {
     // Parse the input files.
     let mut syntax_trees = vec![];
 
+    // Parsing deeply nested sources recurses heavily, give the parser threads enough stack. Files
+    // are only parsed by a pool of workers unless they are parsed sequentially.
+    let stack_size = parse_stack_size * 1024 * 1024;
+    let pool = (!(force_sequential || propagate_defines))
+        .then(|| {
+            rayon::ThreadPoolBuilder::new()
+                .stack_size(stack_size)
+                .build()
+        })
+        .transpose()
+        .context("Failed to create the parser thread pool")?;
+
+    // Comments are kept in the given files even if they are stripped everywhere else. Files are
+    // compared by their canonical path, such that relative and absolute paths match.
+    let strip_comments_in = |filename: &str| {
+        strip_comments
+            && !keep_comments_in
+                .contains(&std::fs::canonicalize(filename).unwrap_or_else(|_| filename.into()))
+    };
+
     let mut internal_defines: Defines = HashMap::new();
 
     for bundle in file_list {
@@ -342,40 +379,61 @@ pub fn build_syntax_tree(
         // Use a neat trick of `collect` here, which allows you to collect a
         // `Result<T>` iterator into a `Result<Vec<T>>`, i.e. bubbling up the
         // error.
-        let v = if force_sequential | propagate_defines {
-            let tmp = bundle.files.iter().map(|filename| -> Result<_> {
-                let pf = parse_file(
-                    filename,
-                    &bundle_include_dirs,
-                    &internal_defines,
-                    strip_comments,
-                )?;
-                if propagate_defines {
-                    internal_defines.extend(pf.defines.clone());
-                }
-                Ok(pf)
-            });
-            if ignore_unparseable {
-                tmp.filter_map(|r| r.map_err(|e| warn!("Continuing with {:?}", e)).ok())
-                    .collect()
-            } else {
-                tmp.collect::<Result<Vec<ParsedFile>>>()?
-            }
-        } else {
-            let tmp = bundle.files.par_iter().map(|filename| -> Result<_> {
-                parse_file(
-                    filename,
-                    &bundle_include_dirs,
-                    &internal_defines,
-                    strip_comments,
-                )
-            });
-            if ignore_unparseable {
-                tmp.filter_map(|r| r.map_err(|e| warn!("Continuing with {:?}", e)).ok())
-                    .collect()
-            } else {
-                tmp.collect::<Result<Vec<ParsedFile>>>()?
-            }
+        let v = match &pool {
+            // The sequential parse runs on a thread of its own, with the same stack as the workers.
+            None => std::thread::scope(|scope| {
+                std::thread::Builder::new()
+                    .stack_size(stack_size)
+                    .spawn_scoped(scope, || -> Result<Vec<ParsedFile>> {
+                        let tmp = bundle.files.iter().map(|filename| -> Result<_> {
+                            let pf = parse_file(
+                                filename,
+                                &bundle_include_dirs,
+                                &internal_defines,
+                                strip_comments_in(filename),
+                                keep_pragmas,
+                                encoding,
+                                collect_conditionals,
+                                collect_line_marks,
+                            )?;
+                            // The defines after parsing start out as the given ones, but lack
+                            // those removed by `` `undef `` and `` `undefineall ``.
+                            if propagate_defines {
+                                internal_defines = pf.defines.clone();
+                            }
+                            Ok(pf)
+                        });
+                        Ok(if ignore_unparseable {
+                            tmp.filter_map(|r| r.map_err(|e| warn!("Continuing with {:?}", e)).ok())
+                                .collect()
+                        } else {
+                            tmp.collect::<Result<Vec<ParsedFile>>>()?
+                        })
+                    })
+                    .context("Failed to create the parser thread")?
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })?,
+            Some(pool) => pool.install(|| -> Result<Vec<ParsedFile>> {
+                let tmp = bundle.files.par_iter().map(|filename| -> Result<_> {
+                    parse_file(
+                        filename,
+                        &bundle_include_dirs,
+                        &internal_defines,
+                        strip_comments_in(filename),
+                        keep_pragmas,
+                        encoding,
+                        collect_conditionals,
+                        collect_line_marks,
+                    )
+                });
+                Ok(if ignore_unparseable {
+                    tmp.filter_map(|r| r.map_err(|e| warn!("Continuing with {:?}", e)).ok())
+                        .collect()
+                } else {
+                    tmp.collect::<Result<Vec<ParsedFile>>>()?
+                })
+            })?,
         };
         syntax_trees.extend(v);
     }
@@ -383,25 +441,70 @@ pub fn build_syntax_tree(
     Ok(syntax_trees)
 }
 
-pub fn just_preprocess(syntax_trees: Vec<ParsedFile>, mut out: Box<dyn Write>) -> Result<()> {
-    write!(
-        out,
-        "// Compiled by morty-{} / {}\n\n",
-        env!("CARGO_PKG_VERSION"),
-        OffsetDateTime::now_local().unwrap_or(OffsetDateTime::now_utc())
-    )
-    .unwrap();
+/// Write the preprocessed sources, preceded by `header`. Unless `quiet`, the name of each file is
+/// printed to stderr. With `line_directives`, `` `line `` directives map the output back to the
+/// original files.
+pub fn just_preprocess(
+    syntax_trees: Vec<ParsedFile>,
+    mut out: Box<dyn Write>,
+    header: &str,
+    quiet: bool,
+    line_directives: bool,
+) -> Result<()> {
+    write!(out, "{}", header).unwrap();
     for pf in syntax_trees {
-        eprintln!("{}:", pf.path);
-        writeln!(out, "{:}", pf.source).unwrap();
+        if !quiet {
+            eprintln!("{}:", pf.path);
+        }
+        let source = apply_replacements(&pf.path, &pf.source, pf.stripped_comments.clone())?;
+        if !line_directives {
+            writeln!(out, "{:}", source).unwrap();
+            continue;
+        }
+        let mut marks = pf.line_marks.iter().peekable();
+        for (index, line) in source.split('\n').enumerate() {
+            if index > 0 {
+                writeln!(out)?;
+            }
+            if let Some(mark) = marks.next_if(|m| m.index == index) {
+                writeln!(out, "`line {} \"{}\" 0", mark.line, mark.path)?;
+            }
+            write!(out, "{}", line)?;
+        }
+        writeln!(out)?;
     }
     Ok(())
 }
 
-pub fn build_doc(syntax_trees: Vec<ParsedFile>, dir: &str) -> Result<()> {
-    let doc = doc::Doc::new(&syntax_trees);
-    let mut html = doc::Renderer::new(Path::new(dir));
-    html.render(&doc)?;
+pub fn build_doc(
+    syntax_trees: Vec<ParsedFile>,
+    dir: Option<&String>,
+    single_file: Option<&String>,
+    json_file: Option<&String>,
+    omitted: Vec<String>,
+    source_snippets: bool,
+) -> Result<()> {
+    let mut doc = doc::Doc::new(&syntax_trees);
+    if !omitted.is_empty() {
+        warn!(
+            "{} files could not be parsed and are missing from the documentation",
+            omitted.len()
+        );
+    }
+    doc.omitted = omitted;
+    if let Some(dir) = dir {
+        let mut html = doc::Renderer::new(Path::new(dir));
+        html.source_snippets = source_snippets;
+        html.render(&doc)?;
+    }
+    if let Some(single_file) = single_file {
+        let mut html = doc::Renderer::single_page(Path::new(single_file));
+        html.source_snippets = source_snippets;
+        html.render(&doc)?;
+    }
+    if let Some(json_file) = json_file {
+        doc::JsonRenderer::new(Path::new(json_file)).render(&doc)?;
+    }
     Ok(())
 }
 
@@ -412,15 +515,10 @@ pub fn write_manifest(
     include_dirs: Vec<String>,
     defines: HashMap<String, Option<String>>,
     top_module: Option<&String>,
+    keep_only_tops: bool,
+    includes: Option<&HashMap<String, Vec<String>>>,
 ) -> Result<()> {
-    let mut undef_modules = Vec::new();
-
-    // find undefined modules
-    for name in &pickle.inst_table {
-        if !pickle.rename_table.contains_key(name) {
-            undef_modules.push(name.to_string());
-        }
-    }
+    let undef_modules = pickle.get_undefined_modules();
 
     let mut top_modules = Vec::new();
 
@@ -465,11 +563,58 @@ pub fn write_manifest(
         bundles.push(FileBundle {
             include_dirs,
             export_incdirs: HashMap::new(),
+            base_dir: None,
             defines,
             files: base_files,
         });
     }
 
+    // Only list the files declaring the top modules.
+    if keep_only_tops {
+        let top_files: HashSet<&String> = pickle
+            .rename_table
+            .iter()
+            .filter(|(_, new_name)| top_modules.contains(new_name))
+            .filter_map(|(name, _)| pickle.module_file_map.get(name))
+            .collect();
+        for bundle in &mut bundles {
+            bundle.files.retain(|file| top_files.contains(file));
+        }
+        bundles.retain(|bundle| !bundle.files.is_empty());
+    }
+
+    // Only keep the include directories which the headers included by the listed files were
+    // resolved in. A header lies in the deepest include directory containing it. Bundles with files
+    // of unknown includes, such as library files, keep all their include directories.
+    if let Some(includes) = includes {
+        let canonical = |path: &str| std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        for bundle in &mut bundles {
+            if !bundle.files.iter().all(|file| includes.contains_key(file)) {
+                continue;
+            }
+            let dirs: Vec<PathBuf> = bundle
+                .include_dirs
+                .iter()
+                .map(|x| canonical(x.as_str()))
+                .collect();
+            let mut reachable = HashSet::new();
+            for header in bundle.files.iter().flat_map(|file| &includes[file]) {
+                let header = canonical(header.as_str());
+                if let Some(dir) = dirs
+                    .iter()
+                    .filter(|dir| header.starts_with(dir))
+                    .max_by_key(|dir| dir.components().count())
+                {
+                    reachable.insert(dir.clone());
+                }
+            }
+            let mut dirs = dirs.iter();
+            bundle
+                .include_dirs
+                .retain(|_| reachable.contains(dirs.next().unwrap()));
+        }
+    }
+
     let json = serde_json::to_string_pretty(&Manifest {
         sources: bundles,
         tops: top_modules,
@@ -484,267 +629,2666 @@ pub fn write_manifest(
     Ok(())
 }
 
-/// Write module graph to file
-pub fn write_dot_graph(pickle: &Pickle, graph_file: &str) -> Result<()> {
-    let path = Path::new(graph_file);
+/// Write the undefined modules to file, one per line.
+pub fn write_undefined_modules(pickle: &Pickle, undefined_file: &str) -> Result<()> {
+    let path = Path::new(undefined_file);
     let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
-    writeln!(
-        out,
-        "{:?}",
-        petgraph::dot::Dot::with_config(
-            &pickle.module_graph,
-            &[petgraph::dot::Config::EdgeNoLabel]
-        )
-    )
-    .unwrap();
+    for name in pickle.get_undefined_modules() {
+        writeln!(out, "{}", name)?;
+    }
     Ok(())
 }
 
-/// Struct containing information about
-/// what should be pickled and how.
-#[derive(Debug)]
-pub struct Pickle<'a> {
-    /// Optional name prefix.
-    pub prefix: Option<&'a String>,
-    /// Optional name suffix.
-    pub suffix: Option<&'a String>,
-    /// Declarations which are excluded from re-naming.
-    pub exclude_rename: HashSet<&'a String>,
-    /// Declarations which are excluded from the pickled sources.
-    pub exclude: HashSet<&'a String>,
-    /// Table containing thing that should be re-named.
-    pub rename_table: HashMap<String, String>,
-    /// Locations of text which should be replaced.
-    pub replace_table: Vec<(usize, usize, String)>,
-    /// A set of instantiated modules.
-    pub inst_table: HashSet<String>,
-    /// Information for library files
-    pub libs: LibraryBundle,
-    /// List of library files used during parsing.
-    pub used_libs: Vec<String>,
-    /// Module hierarchy graph
-    pub module_graph: Graph<String, ()>,
-    /// Map for module names to graph nodes
-    pub module_graph_nodes: HashMap<String, NodeIndex>,
-    /// Map module name to declaration file
-    pub module_file_map: HashMap<String, String>,
+/// Write the declarations in `file` and the modules declared elsewhere they depend on.
+pub fn write_file_dependencies(pickle: &Pickle, file: &str, mut out: impl Write) -> Result<()> {
+    let (declared, instantiated) = pickle.get_file_dependencies(file);
+    writeln!(out, "declares: {}", declared.join(" "))?;
+    writeln!(out, "instantiates: {}", instantiated.join(" "))?;
+    Ok(())
 }
 
-impl<'a> Pickle<'a> {
-    pub fn new(
-        prefix: Option<&'a String>,
-        suffix: Option<&'a String>,
-        exclude_rename: HashSet<&'a String>,
-        exclude: HashSet<&'a String>,
-        libs: LibraryBundle,
-    ) -> Self {
-        Self {
-            prefix,
-            suffix,
-            exclude_rename,
-            exclude,
-            // Create a rename table.
-            rename_table: HashMap::new(),
-            replace_table: vec![],
-            inst_table: HashSet::new(),
-            libs,
-            used_libs: vec![],
-            // Create graph.
-            module_graph: Graph::new(),
-            module_graph_nodes: HashMap::new(),
-            module_file_map: HashMap::new(),
-        }
+/// Write all declarations as `<type> <name> <file>:<line>`, sorted by name.
+pub fn write_module_list(pickle: &Pickle, mut out: impl Write) -> Result<()> {
+    let mut names: Vec<&String> = pickle.module_type_map.keys().collect();
+    names.sort();
+    for name in names {
+        writeln!(
+            out,
+            "{} {} {}:{}",
+            pickle.module_type_map[name],
+            name,
+            pickle.module_file_map[name],
+            pickle.module_loc_map[name].line
+        )?;
     }
+    Ok(())
+}
 
-    /// Register a declaration such as a package or module.
-    pub fn register_declaration(&mut self, syntax_tree: &SyntaxTree, id: RefNode, file: String) {
-        let (module_name, loc) = get_identifier(syntax_tree, id);
-        info!("module_name: {:?}", module_name);
-        self.module_graph_nodes.insert(
-            module_name.clone(),
-            self.module_graph.add_node(module_name.clone()),
-        );
-        self.module_file_map.insert(module_name.clone(), file);
-        if self.exclude_rename.contains(&module_name) || self.exclude.contains(&module_name) {
-            return;
-        }
-        let mut new_name = module_name.clone();
-        if let Some(prefix) = self.prefix {
-            new_name = format!("{}{}", prefix, new_name);
+/// Write a Makefile dependency file, making `target` depend on all sources and their includes.
+pub fn write_depfile(depfile: &str, target: &str, syntax_trees: &[ParsedFile]) -> Result<()> {
+    let escape = |path: &str| path.replace(' ', "\\ ").replace('$', "$$");
+    let mut deps: Vec<&String> = vec![];
+    for pf in syntax_trees {
+        for dep in std::iter::once(&pf.path).chain(&pf.includes) {
+            if !deps.contains(&dep) {
+                deps.push(dep);
+            }
         }
-        if let Some(suffix) = self.suffix {
+    }
+
+    let path = Path::new(depfile);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    write!(out, "{}:", escape(target))?;
+    for dep in &deps {
+        write!(out, " \\\n  {}", escape(dep))?;
+    }
+    writeln!(out)?;
+    // Phony targets such that removed files do not break the build.
+    for dep in &deps {
+        writeln!(out, "\n{}:", escape(dep))?;
+    }
+    Ok(())
+}
+
+/// Write the files emitted into the pickle as a file list, one path per line in emission order.
+pub fn write_ordered_flist(
+    pickle: &Pickle,
+    flist: &str,
+    syntax_trees: &[ParsedFile],
+    files: Option<&HashSet<String>>,
+) -> Result<()> {
+    let path = Path::new(flist);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    for pf in pickle.get_emitted_files(syntax_trees, files) {
+        writeln!(out, "{}", pf.path)?;
+    }
+    Ok(())
+}
+
+/// Write the instance hierarchy below `top` to a file.
+pub fn write_hierarchy(pickle: &Pickle, hierarchy_file: &str, top: &str) -> Result<()> {
+    let path = Path::new(hierarchy_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    write!(out, "{}", pickle.get_hierarchy(top))?;
+    Ok(())
+}
+
+/// Write the files which were parsed, in order, with the include directories and defines they were
+/// parsed with as JSON. `parsed` holds the files given in `file_list` which could be parsed, the
+/// library files loaded by the pickle follow them.
+pub fn write_parsed_file_list(
+    parsed_file_list: &str,
+    pickle: &Pickle,
+    file_list: &[FileBundle],
+    parsed: &HashSet<String>,
+) -> Result<()> {
+    let mut entries = vec![];
+    for bundle in file_list {
+        for file in bundle.files.iter().filter(|x| parsed.contains(*x)) {
+            entries.push(ParsedFileEntry {
+                file,
+                include_dirs: &bundle.include_dirs,
+                defines: bundle.defines.iter().collect(),
+                library: false,
+            });
+        }
+    }
+    for file in &pickle.used_libs {
+        entries.push(ParsedFileEntry {
+            file,
+            include_dirs: &pickle.libs.include_dirs,
+            defines: pickle.libs.defines.iter().collect(),
+            library: true,
+        });
+    }
+    let path = Path::new(parsed_file_list);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "{}", serde_json::to_string_pretty(&entries)?)?;
+    Ok(())
+}
+
+/// Write the conditional compilation directives of each parsed file, and whether their branches
+/// were taken, as JSON.
+pub fn write_ifdef_report(syntax_trees: &[ParsedFile], report_file: &str) -> Result<()> {
+    #[derive(Serialize)]
+    struct Entry<'a> {
+        file: &'a str,
+        conditionals: &'a [Conditional],
+    }
+    let entries: Vec<Entry> = syntax_trees
+        .iter()
+        .filter_map(|pf| match &pf.conditionals {
+            Some(conditionals) if !conditionals.is_empty() => Some(Entry {
+                file: &pf.path,
+                conditionals,
+            }),
+            _ => None,
+        })
+        .collect();
+    let path = Path::new(report_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "{}", serde_json::to_string_pretty(&entries)?)?;
+    Ok(())
+}
+
+/// Warn about macros tested by conditional compilation directives, but never defined on the command
+/// line, in a manifest, or in the sources. Returns the macros along with the file and line of each
+/// test.
+pub fn check_undefined_defines(syntax_trees: &[ParsedFile]) -> Vec<(String, String, usize)> {
+    let defined: HashSet<&str> = syntax_trees
+        .iter()
+        .flat_map(|pf| pf.defines.keys())
+        .map(String::as_str)
+        .collect();
+    let mut undefined = vec![];
+    for pf in syntax_trees {
+        for conditional in pf.conditionals.iter().flatten() {
+            let name = match conditional.tested_macro() {
+                Some(name) if !defined.contains(name) => name,
+                _ => continue,
+            };
+            warn!(
+                "Macro `{}` tested at {}:{} is never defined",
+                name, pf.path, conditional.line
+            );
+            undefined.push((name.to_string(), pf.path.clone(), conditional.line));
+        }
+    }
+    undefined
+}
+
+/// Write the statistics of a pickle as JSON.
+pub fn write_stats(stats: &PickleStats, stats_file: &str) -> Result<()> {
+    let path = Path::new(stats_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "{}", serde_json::to_string_pretty(stats)?)?;
+    Ok(())
+}
+
+/// Write the includes resolved while preprocessing each parsed file, along with the absolute path
+/// they resolved to.
+pub fn write_includes(includes_file: &str, syntax_trees: &[ParsedFile]) -> Result<()> {
+    let path = Path::new(includes_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    for pf in syntax_trees {
+        writeln!(out, "{}", pf.path)?;
+        for include in &pf.includes {
+            let resolved = std::fs::canonicalize(include).unwrap_or_else(|_| include.into());
+            writeln!(out, "    {} -> {}", include, resolved.display())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the headers included by each parsed file as JSON, mapping the file to its includes in
+/// order of appearance.
+pub fn write_include_deps(include_deps_file: &str, syntax_trees: &[ParsedFile]) -> Result<()> {
+    let deps: BTreeMap<&String, &Vec<String>> = syntax_trees
+        .iter()
+        .map(|pf| (&pf.path, &pf.includes))
+        .collect();
+    let path = Path::new(include_deps_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "{}", serde_json::to_string_pretty(&deps)?)?;
+    Ok(())
+}
+
+/// Write the defines of each parsed file as JSON, mapping the file to the names and texts of its
+/// defines.
+pub fn write_defines(defines_file: &str, syntax_trees: &[ParsedFile]) -> Result<()> {
+    let defines: BTreeMap<&String, BTreeMap<&String, Option<&String>>> = syntax_trees
+        .iter()
+        .map(|pf| {
+            let defines = pf
+                .defines
+                .iter()
+                .map(|(name, define)| {
+                    let text = define.as_ref().and_then(|d| d.text.as_ref());
+                    (name, text.map(|t| &t.text))
+                })
+                .collect();
+            (&pf.path, defines)
+        })
+        .collect();
+    let path = Path::new(defines_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "{}", serde_json::to_string_pretty(&defines)?)?;
+    Ok(())
+}
+
+/// Write module graph to file
+pub fn write_dot_graph(pickle: &Pickle, graph_file: &str) -> Result<()> {
+    let path = Path::new(graph_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(
+        out,
+        "{:?}",
+        petgraph::dot::Dot::with_config(
+            &pickle.module_graph,
+            &[petgraph::dot::Config::EdgeNoLabel]
+        )
+    )
+    .unwrap();
+    Ok(())
+}
+
+/// Write module graph to file in JSON format
+pub fn write_json_graph(pickle: &Pickle, graph_file: &str) -> Result<()> {
+    let path = Path::new(graph_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "{}", pickle.get_graph_json())?;
+    Ok(())
+}
+
+/// Write module graph to file in GraphML format
+pub fn write_graphml_graph(pickle: &Pickle, graph_file: &str) -> Result<()> {
+    let path = Path::new(graph_file);
+    let mut out = Box::new(BufWriter::new(File::create(path).unwrap())) as Box<dyn Write>;
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        out,
+        "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">"
+    )?;
+    writeln!(
+        out,
+        "  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>"
+    )?;
+    writeln!(
+        out,
+        "  <key id=\"type\" for=\"node\" attr.name=\"type\" attr.type=\"string\"/>"
+    )?;
+    writeln!(out, "  <graph id=\"G\" edgedefault=\"directed\">")?;
+    for idx in pickle.module_graph.node_indices() {
+        let name = &pickle.module_graph[idx];
+        let ty = match pickle.module_type_map.get(name) {
+            Some(ty) => ty.to_string(),
+            // Instantiated, but never declared.
+            None => "undefined".to_string(),
+        };
+        writeln!(
+            out,
+            "    <node id=\"n{}\"><data key=\"label\">{}</data><data key=\"type\">{}</data></node>",
+            idx.index(),
+            xml_escape(name),
+            ty
+        )?;
+    }
+    for edge in pickle.module_graph.raw_edges() {
+        writeln!(
+            out,
+            "    <edge source=\"n{}\" target=\"n{}\"/>",
+            edge.source().index(),
+            edge.target().index()
+        )?;
+    }
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</graphml>")?;
+    Ok(())
+}
+
+// Escape the characters which are not allowed verbatim in XML character data.
+pub(crate) fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write the checksum of the pickle to file, in the format understood by `sha256sum -c`.
+pub fn write_checksum(checksum_file: &str, hasher: &Arc<Mutex<Sha256>>, name: &str) -> Result<()> {
+    let digest = hasher.lock().unwrap().clone().finalize();
+    let mut out = File::create(checksum_file)
+        .with_context(|| format!("Failed to create `{}`", checksum_file))?;
+    writeln!(out, "{:x}  {}", digest, name)?;
+    Ok(())
+}
+
+/// A writer computing the SHA-256 checksum of everything written through it.
+pub struct ChecksumWriter {
+    inner: Box<dyn Write>,
+    hasher: Arc<Mutex<Sha256>>,
+}
+
+impl ChecksumWriter {
+    pub fn new(inner: Box<dyn Write>) -> Self {
+        Self {
+            inner,
+            hasher: Arc::new(Mutex::new(Sha256::new())),
+        }
+    }
+
+    /// Handle to the hasher, which remains valid once the writer has been consumed.
+    pub fn hasher(&self) -> Arc<Mutex<Sha256>> {
+        self.hasher.clone()
+    }
+}
+
+impl Write for ChecksumWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.lock().unwrap().update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer collecting everything written through it in memory, optionally passing it on to an
+/// inner writer.
+#[derive(Default)]
+pub struct CaptureWriter {
+    inner: Option<Box<dyn Write>>,
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl CaptureWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tee(inner: Box<dyn Write>) -> Self {
+        Self {
+            inner: Some(inner),
+            ..Default::default()
+        }
+    }
+
+    /// Handle to the captured bytes, which remains valid once the writer has been consumed.
+    pub fn buffer(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.buffer.clone()
+    }
+}
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = match &mut self.inner {
+            Some(inner) => inner.write(buf)?,
+            None => buf.len(),
+        };
+        self.buffer.lock().unwrap().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parse a generated pickle again, failing if it is not valid SystemVerilog.
+pub fn verify_pickle(pickle: &str) -> Result<()> {
+    let no_include_dirs: &[&Path] = &[];
+    parse_sv_str(
+        pickle,
+        PathBuf::from("pickle"),
+        &HashMap::new(),
+        no_include_dirs,
+        false,
+        false,
+    )
+    .map_err(|err| anyhow!("The generated pickle does not parse: {}", err))?;
+    Ok(())
+}
+
+/// Write the declarations of `pickle` which are new or whose content differs from the
+/// declaration of the same name in the baseline pickle.
+pub fn write_pickle_diff(baseline_file: &str, pickle: &str, mut out: impl Write) -> Result<()> {
+    let baseline = std::fs::read_to_string(baseline_file)
+        .with_context(|| format!("Failed to read baseline `{}`", baseline_file))?;
+    let baseline: HashMap<String, _> = split_declarations(baseline_file, &baseline)?
+        .into_iter()
+        .map(|(name, text)| (name, Sha256::digest(text.as_bytes())))
+        .collect();
+
+    let declarations = split_declarations("pickle", pickle)?;
+    for (name, text) in &declarations {
+        if baseline.get(name) == Some(&Sha256::digest(text.as_bytes())) {
+            continue;
+        }
+        debug!("Declaration `{}` differs from the baseline", name);
+        writeln!(out, "{}", text)?;
+    }
+    for name in baseline.keys() {
+        if !declarations.iter().any(|(x, _)| x == name) {
+            info!("Declaration `{}` was removed from the baseline", name);
+        }
+    }
+    Ok(())
+}
+
+// Split a pickle into its module, interface and package declarations, keyed by name. The
+// declarations are cut from the text along their locations in the syntax tree, mapped back through
+// the preprocessor such that they retain their macros.
+fn split_declarations(path: &str, text: &str) -> Result<Vec<(String, String)>> {
+    let no_include_dirs: &[&Path] = &[];
+    let (st, _) = parse_sv_str(
+        text,
+        PathBuf::from(path),
+        &HashMap::new(),
+        no_include_dirs,
+        false,
+        false,
+    )
+    .map_err(|err| anyhow!("Failed to parse `{}`: {}", path, err))?;
+
+    let mut declarations = vec![];
+    let mut end = 0;
+    for node in &st {
+        let id = match node {
+            RefNode::ModuleDeclarationAnsi(x) => unwrap_node!(x, ModuleIdentifier),
+            RefNode::ModuleDeclarationNonansi(x) => unwrap_node!(x, ModuleIdentifier),
+            RefNode::InterfaceDeclaration(x) if !is_extern_interface(x) => {
+                unwrap_node!(x, InterfaceIdentifier)
+            }
+            RefNode::PackageDeclaration(x) => unwrap_node!(x, PackageIdentifier),
+            _ => continue,
+        };
+        // The first and last token of the declaration, located in the original text.
+        let mut locs = node.clone().into_iter().filter_map(|x| match x {
+            RefNode::Locate(x) => match st.get_origin(x) {
+                Some((origin, offset)) if origin == Path::new(path) => Some((offset, x.len)),
+                _ => None,
+            },
+            _ => None,
+        });
+        let (first, last) = match (locs.next(), locs.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => continue,
+        };
+        // Nested declarations are part of the enclosing one.
+        if first.0 < end {
+            continue;
+        }
+        let body = match text.get(first.0..last.0 + last.1) {
+            Some(body) => body,
+            None => continue,
+        };
+        end = last.0 + last.1;
+        let name = get_identifier(&st, id.unwrap()).0;
+        declarations.push((name, body.to_string()));
+    }
+    Ok(declarations)
+}
+
+/// Format the custom banner followed by the `// Compiled by morty` line, unless `no_header`. The
+/// line carries the current date unless `no_timestamp`.
+pub fn format_header(
+    banner: Option<&str>,
+    no_header: bool,
+    no_timestamp: bool,
+    comment_style: CommentStyle,
+) -> String {
+    let mut header = String::new();
+    if let Some(banner) = banner {
+        header.push_str(banner.trim_end());
+        header.push('\n');
+        if no_header {
+            header.push('\n');
+        }
+    }
+    if !no_header {
+        let mut compiled = format!("Compiled by morty-{}", env!("CARGO_PKG_VERSION"));
+        if !no_timestamp {
+            compiled.push_str(&format!(
+                " / {}",
+                OffsetDateTime::now_local().unwrap_or(OffsetDateTime::now_utc())
+            ));
+        }
+        header.push_str(&format!("{}\n\n", comment_style.comment(&compiled)));
+    }
+    header
+}
+
+/// Style of the comments morty adds to the pickle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentStyle {
+    /// `// comment`
+    Line,
+    /// `/* comment */`
+    Block,
+}
+
+impl Default for CommentStyle {
+    fn default() -> Self {
+        CommentStyle::Line
+    }
+}
+
+impl CommentStyle {
+    /// Format a single-line comment.
+    pub fn comment(&self, text: &str) -> String {
+        match self {
+            CommentStyle::Line => format!("// {}", text),
+            CommentStyle::Block => format!("/* {} */", text),
+        }
+    }
+}
+
+/// Order in which the files are pickled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickleFormat {
+    /// Whole files in the order given.
+    Classic,
+    /// Whole files, moving files declaring packages before the files using them.
+    Graph,
+    /// Whole files, sorted by the alphabetically first of their declarations after renaming.
+    Alpha,
+}
+
+impl Default for PickleFormat {
+    fn default() -> Self {
+        PickleFormat::Graph
+    }
+}
+
+/// Case to which renamed names are normalized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalizeCase {
+    Lower,
+    Upper,
+}
+
+/// Kind of construct a declaration introduces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SVConstructType {
+    Module,
+    Interface,
+    Package,
+    Config,
+}
+
+impl std::str::FromStr for SVConstructType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "module" => Ok(SVConstructType::Module),
+            "interface" => Ok(SVConstructType::Interface),
+            "package" => Ok(SVConstructType::Package),
+            "config" => Ok(SVConstructType::Config),
+            _ => Err(anyhow!(
+                "Unknown construct `{}`, expected `module`, `interface`, `package`, or `config`",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SVConstructType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SVConstructType::Module => write!(f, "module"),
+            SVConstructType::Interface => write!(f, "interface"),
+            SVConstructType::Package => write!(f, "package"),
+            SVConstructType::Config => write!(f, "config"),
+        }
+    }
+}
+
+/// Struct containing information about
+/// what should be pickled and how.
+#[derive(Debug)]
+pub struct Pickle<'a> {
+    /// Optional name prefix.
+    pub prefix: Option<&'a String>,
+    /// Optional name suffix.
+    pub suffix: Option<&'a String>,
+    /// Declarations which are excluded from re-naming.
+    pub exclude_rename: HashSet<&'a String>,
+    /// Patterns of declarations which are excluded from re-naming.
+    pub exclude_rename_patterns: GlobSet,
+    /// Declarations which are excluded from the pickled sources.
+    pub exclude: HashSet<&'a String>,
+    /// Patterns of declarations which are excluded from the pickled sources.
+    pub exclude_patterns: GlobSet,
+    /// Declarations of a specific kind which are excluded from the pickled sources.
+    pub exclude_kinds: HashSet<(SVConstructType, String)>,
+    /// Table containing thing that should be re-named.
+    pub rename_table: HashMap<String, String>,
+    /// Locations of text which should be replaced in the file being pickled. The table is
+    /// rebuilt for each file, hence never needs to be filtered by file.
+    pub replace_table: Vec<(usize, usize, String)>,
+    /// A set of instantiated modules.
+    pub inst_table: HashSet<String>,
+    /// Information for library files
+    pub libs: LibraryBundle,
+    /// List of library files used during parsing.
+    pub used_libs: Vec<String>,
+    /// Module hierarchy graph
+    pub module_graph: Graph<String, ()>,
+    /// Map for module names to graph nodes
+    pub module_graph_nodes: HashMap<String, NodeIndex>,
+    /// Map module name to declaration file
+    pub module_file_map: HashMap<String, String>,
+    /// Map module name to the kind of construct it declares
+    pub module_type_map: HashMap<String, SVConstructType>,
+    /// Map module name to the location of its identifier in the declaration file
+    pub module_loc_map: HashMap<String, Locate>,
+    /// Map module name to the file and offset its declaration originates from, which differ from
+    /// the declaration file for declarations in included headers.
+    pub module_origin_map: HashMap<String, (PathBuf, usize)>,
+    /// Conflicting declarations of an already declared name.
+    pub duplicate_declarations: Vec<String>,
+    /// Parameter defaults of the first declaration of each module, with its file.
+    pub module_params: HashMap<String, (String, Vec<(String, String)>)>,
+    /// Packages inlined into their only user, mapped to their items.
+    pub flattened_packages: HashMap<String, String>,
+    /// Timescale emitted at the top of the pickle.
+    pub timescale: Option<String>,
+    /// Custom text prepended to the pickle.
+    pub banner: Option<String>,
+    /// Omit the `// Compiled by morty` header line.
+    pub no_header: bool,
+    /// Omit the date from the header line, such that the pickle is reproducible.
+    pub no_timestamp: bool,
+    /// Rename instances along with the declarations.
+    pub rename_instances: bool,
+    /// Instances in each declaration, as instance and module name in order of appearance.
+    pub instances: HashMap<String, Vec<(String, String)>>,
+    /// Canonical paths of files excluded from the pickle.
+    pub exclude_files: HashSet<PathBuf>,
+    /// Style of the comments added to the pickle.
+    pub comment_style: CommentStyle,
+    /// Only emit the first of identical global package imports.
+    pub dedup_global_imports: bool,
+    /// Global package imports emitted so far, with normalized whitespace.
+    pub global_imports: HashSet<String>,
+    /// Width in columns at which the connections of an expanded `.*` are wrapped.
+    pub max_line_width: Option<usize>,
+    /// Suppress informational messages on stderr.
+    pub quiet: bool,
+    /// Prepend a table of contents of the declarations to the pickle.
+    pub toc: bool,
+    /// Statistics of the last pickle.
+    pub stats: Option<PickleStats>,
+    /// Strip DPI import and export declarations.
+    pub strip_dpi: bool,
+    /// Expand implicit named port connections such as `.clk` to `.clk(clk)`.
+    pub expand_implicit_ports: bool,
+    /// Case to which names are normalized before applying prefix and suffix.
+    pub normalize_case: Option<NormalizeCase>,
+    /// Order in which the files are pickled.
+    pub pickle_format: PickleFormat,
+    /// Ports of each module and interface, in order of declaration. Only collected to expand
+    /// `.*` port connections.
+    pub module_ports: Option<HashMap<String, Vec<String>>>,
+    /// Emit a comment with the originating file in front of each declaration.
+    pub annotate_sources: bool,
+    /// Strip packages without any items from the pickled sources.
+    pub dedupe_blank_decls: bool,
+}
+
+impl<'a> Pickle<'a> {
+    pub fn new(
+        prefix: Option<&'a String>,
+        suffix: Option<&'a String>,
+        exclude_rename: HashSet<&'a String>,
+        exclude: HashSet<&'a String>,
+        libs: LibraryBundle,
+    ) -> Self {
+        Self {
+            prefix,
+            suffix,
+            exclude_rename_patterns: glob_set(&exclude_rename),
+            exclude_patterns: glob_set(&exclude),
+            exclude_rename,
+            exclude,
+            exclude_kinds: HashSet::new(),
+            // Create a rename table.
+            rename_table: HashMap::new(),
+            replace_table: vec![],
+            inst_table: HashSet::new(),
+            libs,
+            used_libs: vec![],
+            // Create graph.
+            module_graph: Graph::new(),
+            module_graph_nodes: HashMap::new(),
+            module_file_map: HashMap::new(),
+            module_type_map: HashMap::new(),
+            module_loc_map: HashMap::new(),
+            module_origin_map: HashMap::new(),
+            duplicate_declarations: vec![],
+            module_params: HashMap::new(),
+            flattened_packages: HashMap::new(),
+            dedup_global_imports: false,
+            global_imports: HashSet::new(),
+            max_line_width: None,
+            timescale: None,
+            banner: None,
+            no_header: false,
+            no_timestamp: false,
+            rename_instances: false,
+            instances: HashMap::new(),
+            quiet: false,
+            toc: false,
+            stats: None,
+            strip_dpi: false,
+            expand_implicit_ports: false,
+            normalize_case: None,
+            pickle_format: PickleFormat::Graph,
+            module_ports: None,
+            exclude_files: HashSet::new(),
+            comment_style: CommentStyle::Line,
+            annotate_sources: false,
+            dedupe_blank_decls: false,
+        }
+    }
+
+    /// Register all declarations, instantiations and imports of the files in the module graph.
+    /// Library modules are loaded as needed and appended to `syntax_trees`.
+    pub fn build_graph(
+        &mut self,
+        syntax_trees: &mut Vec<ParsedFile>,
+        allow_duplicate_modules: bool,
+    ) -> Result<()> {
+        // Gather information for pickling.
+        for pf in syntax_trees.iter() {
+            for node in &pf.ast {
+                trace!("{:#?}", node);
+                match node {
+                    // Module declarations.
+                    RefNode::ModuleDeclarationAnsi(x) => {
+                        // unwrap_node! gets the nearest ModuleIdentifier from x
+                        let id = unwrap_node!(x, ModuleIdentifier).unwrap();
+                        self.register_parameters(&pf.ast, id.clone(), x.into(), &pf.path);
+                        self.register_declaration(
+                            &pf.ast,
+                            id,
+                            pf.path.clone(),
+                            SVConstructType::Module,
+                        );
+                    }
+                    RefNode::ModuleDeclarationNonansi(x) => {
+                        let id = unwrap_node!(x, ModuleIdentifier).unwrap();
+                        self.register_parameters(&pf.ast, id.clone(), x.into(), &pf.path);
+                        self.register_declaration(
+                            &pf.ast,
+                            id,
+                            pf.path.clone(),
+                            SVConstructType::Module,
+                        );
+                    }
+                    // Interface Declaration.
+                    RefNode::InterfaceDeclaration(x) if !is_extern_interface(x) => {
+                        let id = unwrap_node!(x, InterfaceIdentifier).unwrap();
+                        self.register_declaration(
+                            &pf.ast,
+                            id,
+                            pf.path.clone(),
+                            SVConstructType::Interface,
+                        );
+                    }
+                    // Package declarations.
+                    RefNode::PackageDeclaration(x) => {
+                        let id = unwrap_node!(x, PackageIdentifier).unwrap();
+                        self.register_declaration(
+                            &pf.ast,
+                            id,
+                            pf.path.clone(),
+                            SVConstructType::Package,
+                        );
+                    }
+                    // Configurations.
+                    RefNode::ConfigDeclaration(x) => {
+                        let id = unwrap_node!(x, ConfigIdentifier).unwrap();
+                        self.register_declaration(
+                            &pf.ast,
+                            id,
+                            pf.path.clone(),
+                            SVConstructType::Config,
+                        );
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let quiet = self.quiet;
+        let mut library_files: Vec<ParsedFile> = vec![];
+        let mut cell_uses: Vec<(String, String)> = vec![];
+        for pf in syntax_trees.iter() {
+            // global package import
+            let global_packages = &pf
+                .ast
+                .into_iter()
+                .filter_map(|node| {
+                    if let RefNode::DescriptionPackageItem(x) = node {
+                        if let Some(package_import) = unwrap_node!(x, PackageImportDeclaration) {
+                            let (name, _loc) = get_identifier(
+                                &pf.ast,
+                                unwrap_node!(package_import, SimpleIdentifier, EscapedIdentifier)
+                                    .unwrap(),
+                            );
+                            if !quiet {
+                                eprintln!(
+                                    "Global package import in {}:\n\t{}",
+                                    &pf.path,
+                                    &pf.source[Locate::try_from(x).unwrap().offset
+                                        ..(Locate::try_from(x).unwrap().offset
+                                            + Locate::try_from(x).unwrap().len)]
+                                );
+                            }
+                            Some(name)
+                        } else {
+                            None
+                        }
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            for node in &pf.ast {
+                match node {
+                    // Module declarations.
+                    RefNode::ModuleDeclarationAnsi(x) => {
+                        // unwrap_node! gets the nearest ModuleIdentifier from x
+                        let parent_id = unwrap_node!(x, ModuleIdentifier).unwrap();
+                        let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+
+                        let my_ref_node: RefNode = x.into();
+                        self.find_and_register_instantiations(
+                            &pf.ast,
+                            my_ref_node,
+                            &parent_name,
+                            &mut library_files,
+                        );
+                        for package in global_packages {
+                            self.add_dependency_relation(package, &parent_name);
+                        }
+                    }
+                    RefNode::ModuleDeclarationNonansi(x) => {
+                        let parent_id = unwrap_node!(x, ModuleIdentifier).unwrap();
+                        let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+
+                        let my_ref_node: RefNode = x.into();
+                        self.find_and_register_instantiations(
+                            &pf.ast,
+                            my_ref_node,
+                            &parent_name,
+                            &mut library_files,
+                        );
+                        for package in global_packages {
+                            self.add_dependency_relation(package, &parent_name);
+                        }
+                    }
+                    // Interface Declaration.
+                    RefNode::InterfaceDeclaration(x) if !is_extern_interface(x) => {
+                        let parent_id = unwrap_node!(x, InterfaceIdentifier).unwrap();
+                        let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+
+                        let my_ref_node: RefNode = x.into();
+                        self.find_and_register_instantiations(
+                            &pf.ast,
+                            my_ref_node,
+                            &parent_name,
+                            &mut library_files,
+                        );
+                        for package in global_packages {
+                            self.add_dependency_relation(package, &parent_name);
+                        }
+                    }
+                    // Package declarations.
+                    RefNode::PackageDeclaration(x) => {
+                        let parent_id = unwrap_node!(x, PackageIdentifier).unwrap();
+                        let (parent_name, _) = get_identifier(&pf.ast, parent_id);
+
+                        let my_ref_node: RefNode = x.into();
+                        self.find_and_register_instantiations(
+                            &pf.ast,
+                            my_ref_node,
+                            &parent_name,
+                            &mut library_files,
+                        );
+                        for package in global_packages {
+                            self.add_dependency_relation(package, &parent_name);
+                        }
+                    }
+                    // Bind directives, both inside declarations and at file level.
+                    RefNode::BindDirective(x) => {
+                        self.register_bind(&pf.ast, x, &mut library_files);
+                    }
+                    RefNode::ConfigDeclaration(x) => {
+                        cell_uses.extend(self.register_config(&pf.ast, x, &mut library_files));
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        // A configuration replacing a cell with another one makes all users of the cell depend on
+        // its replacement.
+        for (cell, replacement) in cell_uses {
+            let users: Vec<String> = match self.module_graph_nodes.get(&cell) {
+                Some(n) => self
+                    .module_graph
+                    .neighbors_directed(*n, Direction::Incoming)
+                    .map(|user| self.module_graph[user].clone())
+                    .collect(),
+                None => continue,
+            };
+            for user in users {
+                self.add_dependency_relation(&replacement, &user);
+            }
+        }
+
+        if !self.duplicate_declarations.is_empty() {
+            if !allow_duplicate_modules {
+                return Err(anyhow!(
+                    "Multiple declarations with the same name:\n\t{}",
+                    self.duplicate_declarations.join("\n\t")
+                ));
+            }
+            for duplicate in &self.duplicate_declarations {
+                warn!("Keeping the first declaration: {}", duplicate);
+            }
+        }
+
+        syntax_trees.extend(library_files);
+
+        self.check_rename_collisions()
+    }
+
+    /// Fail if renaming maps different declarations to the same name.
+    pub fn check_rename_collisions(&self) -> Result<()> {
+        let mut originals: HashMap<&String, Vec<&String>> = HashMap::new();
+        for name in self.module_file_map.keys() {
+            let new_name = self.rename_table.get(name).unwrap_or(name);
+            originals.entry(new_name).or_default().push(name);
+        }
+        let mut collisions: Vec<String> = originals
+            .into_iter()
+            .filter(|(_, names)| names.len() > 1)
+            .map(|(new_name, mut names)| {
+                names.sort();
+                format!(
+                    "`{}` renamed to `{}`",
+                    names
+                        .iter()
+                        .map(|x| x.as_str())
+                        .collect::<Vec<_>>()
+                        .join("`, `"),
+                    new_name
+                )
+            })
+            .collect();
+        if collisions.is_empty() {
+            return Ok(());
+        }
+        collisions.sort();
+        Err(anyhow!(
+            "Renaming maps multiple declarations to the same name:\n\t{}",
+            collisions.join("\n\t")
+        ))
+    }
+
+    /// Remember the parameter defaults of a module declaration, warning if a duplicate declaration
+    /// of the module has different defaults.
+    pub fn register_parameters(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        decl: RefNode,
+        file: &str,
+    ) {
+        let (module_name, _) = get_identifier(syntax_tree, id);
+        let params: Vec<(String, String)> = decl
+            .into_iter()
+            .filter_map(|node| match node {
+                RefNode::ParamAssignment(x) => Some((
+                    get_identifier(syntax_tree, (&x.nodes.0).into()).0,
+                    x.nodes
+                        .2
+                        .as_ref()
+                        .and_then(|(_, default)| syntax_tree.get_str(default))
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string(),
+                )),
+                _ => None,
+            })
+            .collect();
+
+        let (first_file, first_params) = match self.module_params.get(&module_name) {
+            Some(x) => x,
+            None => {
+                self.module_params
+                    .insert(module_name, (file.to_string(), params));
+                return;
+            }
+        };
+        for (name, default) in &params {
+            match first_params.iter().find(|(x, _)| x == name) {
+                Some((_, first_default)) if first_default == default => (),
+                first => warn!(
+                    "Duplicate declarations of `{}` differ in the default of `{}`: {} in {}, {} in {}",
+                    module_name,
+                    name,
+                    first.map_or("none", |(_, x)| x.as_str()),
+                    first_file,
+                    default,
+                    file
+                ),
+            }
+        }
+    }
+
+    /// Register a declaration such as a package or module.
+    pub fn register_declaration(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        file: String,
+        ty: SVConstructType,
+    ) {
+        let (module_name, loc) = get_identifier(syntax_tree, id);
+        info!("module_name: {:?}", module_name);
+        // Declarations excluded by kind are not registered at all, such that a declaration of
+        // another kind with the same name does not conflict with them.
+        if self.exclude_kinds.contains(&(ty, module_name.clone())) {
+            return;
+        }
+        // Keep the first declaration, but remember any conflicting one. Copies of a declaration
+        // included into multiple files are no conflict.
+        let origin = syntax_tree
+            .get_origin(&loc)
+            .map(|(path, offset)| (path.clone(), offset));
+        if let (Some(first_file), Some(first_loc)) = (
+            self.module_file_map.get(&module_name),
+            self.module_loc_map.get(&module_name),
+        ) {
+            if (*first_file != file || first_loc.offset != loc.offset)
+                && (origin.is_none() || self.module_origin_map.get(&module_name) != origin.as_ref())
+            {
+                self.duplicate_declarations.push(format!(
+                    "`{}` declared in {}:{} and {}:{}",
+                    module_name, first_file, first_loc.line, file, loc.line
+                ));
+            }
+            return;
+        }
+        // The node may already exist if the declaration was used before.
+        if !self.module_graph_nodes.contains_key(&module_name) {
+            self.module_graph_nodes.insert(
+                module_name.clone(),
+                self.module_graph.add_node(module_name.clone()),
+            );
+        }
+        let excluded_file = self.excludes_file(&file);
+        self.module_file_map.insert(module_name.clone(), file);
+        self.module_type_map.insert(module_name.clone(), ty);
+        self.module_loc_map.insert(module_name.clone(), loc);
+        if let Some(origin) = origin {
+            self.module_origin_map.insert(module_name.clone(), origin);
+        }
+        if self.exclude_rename.contains(&module_name)
+            || self.exclude_rename_patterns.is_match(&module_name)
+            || self.exclude.contains(&module_name)
+            || self.exclude_patterns.is_match(&module_name)
+            || excluded_file
+        {
+            return;
+        }
+        let new_name = self.renamed(&module_name);
+        debug!("Declaration `{}`: {:?}", module_name, loc);
+        self.rename_table.insert(module_name, new_name);
+    }
+
+    /// Whether a file is excluded from the pickle.
+    pub fn excludes_file(&self, path: &str) -> bool {
+        !self.exclude_files.is_empty()
+            && self
+                .exclude_files
+                .contains(&std::fs::canonicalize(path).unwrap_or_else(|_| path.into()))
+    }
+
+    /// Apply prefix and suffix to a name.
+    pub fn renamed(&self, name: &str) -> String {
+        // Escaped identifiers are renamed inside the escape, the terminating whitespace is not
+        // part of the identifier token and remains in place.
+        let (escape, mut new_name) = match name.strip_prefix('\\') {
+            Some(name) => ("\\", name.to_string()),
+            None => ("", name.to_string()),
+        };
+        match self.normalize_case {
+            Some(NormalizeCase::Lower) => new_name = new_name.to_lowercase(),
+            Some(NormalizeCase::Upper) => new_name = new_name.to_uppercase(),
+            None => (),
+        }
+        if let Some(prefix) = self.prefix {
+            new_name = format!("{}{}", prefix, new_name);
+        }
+        if let Some(suffix) = self.suffix {
             new_name = format!("{}{}", new_name, suffix);
         }
-        debug!("Declaration `{}`: {:?}", module_name, loc);
-        self.rename_table.insert(module_name, new_name);
+        format!("{}{}", escape, new_name)
+    }
+
+    pub fn register_instantiation_with_parent(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        parent_name: &str,
+    ) {
+        let (inst_name, _) = get_identifier(syntax_tree, id.clone());
+        self.inst_table.insert(inst_name.clone());
+
+        self.add_dependency_relation(&inst_name, parent_name);
+    }
+
+    pub fn add_dependency_relation(&mut self, inst_name: &str, parent_name: &str) {
+        if !self.module_graph_nodes.contains_key(inst_name) {
+            self.module_graph_nodes.insert(
+                inst_name.to_string(),
+                self.module_graph.add_node(inst_name.to_string()),
+            );
+        }
+        self.module_graph.update_edge(
+            self.module_graph_nodes[parent_name],
+            self.module_graph_nodes[inst_name],
+            (),
+        );
+    }
+
+    /// Remember the names of the instances of a module instantiation in its parent.
+    fn register_instance_names(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        inst: &ModuleInstantiation,
+        module_name: &str,
+        parent_name: &str,
+    ) {
+        let instances = self.instances.entry(parent_name.to_string()).or_default();
+        for node in inst {
+            if let RefNode::NameOfInstance(name) = node {
+                let id = unwrap_node!(name, SimpleIdentifier, EscapedIdentifier).unwrap();
+                let instance = (get_identifier(syntax_tree, id).0, module_name.to_string());
+                // Duplicate declarations of the parent contain the same instances.
+                if !instances.contains(&instance) {
+                    instances.push(instance);
+                }
+            }
+        }
+    }
+
+    pub fn find_and_register_instantiations(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        parent_name: &str,
+        library_files: &mut Vec<ParsedFile>,
+    ) {
+        for node in id {
+            match node {
+                RefNode::ModuleInstantiation(x) => {
+                    let id = unwrap_node!(x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+
+                    let (inst_name, _) = get_identifier(syntax_tree, id.clone());
+                    self.register_instance_names(syntax_tree, x, &inst_name, parent_name);
+                    if !self.rename_table.contains_key(&inst_name) {
+                        info!("Could not find {}, checking libraries...", &inst_name);
+                        self.load_library_module(&inst_name, library_files);
+                    }
+                }
+                RefNode::PackageImportItem(x) => {
+                    let id = unwrap_node!(x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+
+                    let (inst_name, _) = get_identifier(syntax_tree, id);
+                    if !self.rename_table.contains_key(&inst_name) {
+                        info!("Could not find {}, checking libraries...", &inst_name);
+                        self.load_library_module(&inst_name, library_files);
+                    }
+                }
+                RefNode::PackageScope(x) => {
+                    let id = unwrap_node!(x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+
+                    let (inst_name, _) = get_identifier(syntax_tree, id);
+                    if !self.rename_table.contains_key(&inst_name) {
+                        info!("Could not find {}, checking libraries...", &inst_name);
+                        self.load_library_module(&inst_name, library_files);
+                    }
+                }
+                // Generic `interface` ports do not name the interface they depend on.
+                RefNode::InterfacePortHeader(x @ InterfacePortHeader::Identifier(_)) => {
+                    let id = unwrap_node!(x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+
+                    let (inst_name, _) = get_identifier(syntax_tree, id);
+                    if !self.rename_table.contains_key(&inst_name) {
+                        info!("Could not find {}, checking libraries...", &inst_name);
+                        self.load_library_module(&inst_name, library_files);
+                    }
+                }
+                RefNode::ClassScope(x) => {
+                    let id = unwrap_node!(x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+
+                    let (inst_name, _) = get_identifier(syntax_tree, id);
+                    if !self.rename_table.contains_key(&inst_name) {
+                        info!("Could not find {}, checking libraries...", &inst_name);
+                        self.load_library_module(&inst_name, library_files);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Register the dependency introduced by a `bind` directive, such that the bound module is
+    /// retained whenever the bind target is.
+    pub fn register_bind(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        bind: &BindDirective,
+        library_files: &mut Vec<ParsedFile>,
+    ) {
+        let (target, instantiation): (RefNode, RefNode) = match bind {
+            BindDirective::Scope(x) => ((&x.nodes.1).into(), (&x.nodes.3).into()),
+            BindDirective::Instance(x) => {
+                warn!(
+                    "Cannot resolve the module of bind target `{}`, ignoring its dependency.",
+                    syntax_tree.get_str(&x.nodes.1).unwrap_or_default().trim()
+                );
+                return;
+            }
+        };
+        let (target_name, _) = get_identifier(syntax_tree, target);
+        let (bound_name, _) = get_identifier(syntax_tree, instantiation);
+        debug!("Bind `{}` into `{}`", bound_name, target_name);
+
+        if !self.module_graph_nodes.contains_key(&target_name) {
+            self.module_graph_nodes.insert(
+                target_name.clone(),
+                self.module_graph.add_node(target_name.clone()),
+            );
+        }
+        self.inst_table.insert(bound_name.clone());
+        self.add_dependency_relation(&bound_name, &target_name);
+
+        if !self.rename_table.contains_key(&bound_name) {
+            info!("Could not find {}, checking libraries...", &bound_name);
+            self.load_library_module(&bound_name, library_files);
+        }
+    }
+
+    /// Register the dependencies of a configuration on the cells of its design statement and on the
+    /// cells selected by its `use` clauses. Returns the cells replaced by a `cell ... use ...` rule,
+    /// along with their replacement.
+    pub fn register_config(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        config: &ConfigDeclaration,
+        library_files: &mut Vec<ParsedFile>,
+    ) -> Vec<(String, String)> {
+        let (config_name, _) = get_identifier(syntax_tree, (&config.nodes.1).into());
+        if !self.module_graph_nodes.contains_key(&config_name) {
+            return vec![];
+        }
+        let mut cells = vec![];
+        let mut cell_uses = vec![];
+        for node in config {
+            match node {
+                RefNode::DesignStatement(x) => {
+                    cells.extend(x.into_iter().filter_map(|x| match x {
+                        RefNode::CellIdentifier(_) => Some(x),
+                        _ => None,
+                    }));
+                }
+                RefNode::UseClause(x) => cells.extend(unwrap_node!(x, CellIdentifier)),
+                RefNode::ConfigRuleStatementCellUse(x) => {
+                    if let (Some(cell), Some(replacement)) = (
+                        unwrap_node!(&x.nodes.0, CellIdentifier),
+                        unwrap_node!(&x.nodes.1, CellIdentifier),
+                    ) {
+                        cell_uses.push((
+                            get_identifier(syntax_tree, cell).0,
+                            get_identifier(syntax_tree, replacement).0,
+                        ));
+                    }
+                }
+                _ => (),
+            }
+        }
+        for cell in cells {
+            self.register_instantiation_with_parent(syntax_tree, cell.clone(), &config_name);
+            let (cell_name, _) = get_identifier(syntax_tree, cell);
+            debug!("Configuration `{}` uses `{}`", config_name, cell_name);
+            if !self.rename_table.contains_key(&cell_name) {
+                info!("Could not find {}, checking libraries...", &cell_name);
+                self.load_library_module(&cell_name, library_files);
+            }
+        }
+        cell_uses
+    }
+
+    /// Register the usages of renamed declarations in a single node.
+    pub fn register_usages(&mut self, syntax_tree: &SyntaxTree, node: &RefNode) {
+        match node {
+            // Instantiations, end-labels.
+            RefNode::ModuleIdentifier(x) => {
+                let id = unwrap_node!(*x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                self.register_usage(syntax_tree, id);
+            }
+            // Interface identifier.
+            RefNode::InterfaceIdentifier(x) => {
+                let id = unwrap_node!(*x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                self.register_usage(syntax_tree, id);
+            }
+            // Package Qualifier (i.e., explicit package constants).
+            RefNode::ClassScope(x) => {
+                let id = unwrap_node!(*x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                self.register_usage(syntax_tree, id);
+            }
+            // Package Import.
+            RefNode::PackageIdentifier(x) => {
+                let id = unwrap_node!(*x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                self.register_usage(syntax_tree, id);
+            }
+            // Configurations and the cells they select.
+            RefNode::ConfigIdentifier(x) => {
+                let id = unwrap_node!(*x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                self.register_usage(syntax_tree, id);
+            }
+            RefNode::CellIdentifier(x) => {
+                let id = unwrap_node!(*x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                self.register_usage(syntax_tree, id);
+            }
+            _ => (),
+        }
+    }
+
+    /// Register a usage of the identifier.
+    pub fn register_usage(&mut self, syntax_tree: &SyntaxTree, id: RefNode) {
+        let (inst_name, loc) = get_identifier(syntax_tree, id);
+        let new_name = match self.rename_table.get(&inst_name) {
+            Some(x) => x,
+            None => return,
+        };
+        debug!("Usage `{}`: {:?}", inst_name, loc);
+        self.replace_table
+            .push((loc.offset, loc.len, new_name.clone()));
+    }
+
+    // Check whether a given declaration should be striped from the sources. If a `keep` set is
+    // given, declarations not contained in it are striped as well. Returns whether the
+    // declaration was striped.
+    pub fn register_exclude(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        ty: SVConstructType,
+        locate: Locate,
+        keep: Option<&HashSet<String>>,
+    ) -> bool {
+        let (inst_name, loc) = get_identifier(syntax_tree, id);
+        if self.exclude.contains(&inst_name)
+            || self.exclude_patterns.is_match(&inst_name)
+            || self.exclude_kinds.contains(&(ty, inst_name.clone()))
+            || keep.is_some_and(|k| !k.contains(&inst_name))
+        {
+            debug!("Exclude `{}`: {:?}", inst_name, loc);
+            self.replace_table
+                .push((locate.offset, locate.len, "".to_string()));
+            return true;
+        }
+        false
+    }
+
+    // Strip a declaration which conflicts with an earlier declaration of the same name, which
+    // takes precedence. Returns whether the declaration was striped.
+    fn register_duplicate(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        path: &str,
+        locate: Locate,
+    ) -> bool {
+        let (name, loc) = get_identifier(syntax_tree, id);
+        let duplicate = match (
+            self.module_file_map.get(&name),
+            self.module_loc_map.get(&name),
+        ) {
+            (Some(first_file), Some(first_loc)) => {
+                first_file != path || first_loc.offset != loc.offset
+            }
+            _ => false,
+        };
+        if duplicate {
+            debug!("Duplicate `{}`: {:?}", name, loc);
+            self.replace_table
+                .push((locate.offset, locate.len, "".to_string()));
+        }
+        duplicate
+    }
+
+    // Strip a package which is inlined into its only user. Returns whether the package was
+    // striped.
+    fn register_flattened_package(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        id: RefNode,
+        locate: Locate,
+    ) -> bool {
+        let (name, _) = get_identifier(syntax_tree, id);
+        if !self.flattened_packages.contains_key(&name) {
+            return false;
+        }
+        debug!("Flattened `{}`: {:?}", name, locate);
+        self.replace_table
+            .push((locate.offset, locate.len, "".to_string()));
+        true
+    }
+
+    // Strip a package without any items from the sources, if requested. Modules still depending
+    // on the package are reported. Returns whether the package was striped.
+    fn register_blank_package(
+        &mut self,
+        syntax_tree: &SyntaxTree,
+        decl: &PackageDeclaration,
+        locate: Locate,
+    ) -> bool {
+        if !self.dedupe_blank_decls || unwrap_node!(decl, PackageItem).is_some() {
+            return false;
+        }
+        let (name, _) = get_identifier(syntax_tree, unwrap_node!(decl, PackageIdentifier).unwrap());
+        debug!("Blank package `{}`: {:?}", name, locate);
+        if let Some(node) = self.module_graph_nodes.get(&name) {
+            let dependents: Vec<_> = self
+                .module_graph
+                .neighbors_directed(*node, Direction::Incoming)
+                .map(|n| self.module_graph[n].as_str())
+                .collect();
+            if !dependents.is_empty() {
+                warn!(
+                    "Removing blank package `{}`, which is still used by: {}",
+                    name,
+                    dependents.join(", ")
+                );
+            }
+        }
+        self.replace_table
+            .push((locate.offset, locate.len, "".to_string()));
+        true
+    }
+
+    // Annotate a declaration with the file it originates from, if requested.
+    fn register_source_annotation(&mut self, path: &str, locate: Locate) {
+        if self.annotate_sources {
+            let comment = self.comment_style.comment(&format!("from {}", path));
+            self.replace_table
+                .push((locate.offset, 0, format!("{}\n", comment)));
+        }
+    }
+
+    // Load the module with name 'module_name' and append the resulting ParsedFile to 'files'.
+    // This function may recursively load other modules if the library uses another library module.
+    // If no module is found in the library bundle, this function does nothing.
+    pub fn load_library_module(&mut self, module_name: &str, files: &mut Vec<ParsedFile>) {
+        let rm = self.libs.load_module(module_name, &mut self.used_libs);
+        match rm {
+            Ok(pf) => {
+                // register all declarations from this library file.
+                let mut declarations = vec![];
+                for node in &pf.ast {
+                    let (id, ty) = match node {
+                        RefNode::ModuleDeclarationAnsi(x) => (
+                            unwrap_node!(x, ModuleIdentifier).unwrap(),
+                            SVConstructType::Module,
+                        ),
+                        RefNode::ModuleDeclarationNonansi(x) => (
+                            unwrap_node!(x, ModuleIdentifier).unwrap(),
+                            SVConstructType::Module,
+                        ),
+                        RefNode::InterfaceDeclaration(x) if !is_extern_interface(x) => (
+                            unwrap_node!(x, InterfaceIdentifier).unwrap(),
+                            SVConstructType::Interface,
+                        ),
+                        RefNode::PackageDeclaration(x) => (
+                            unwrap_node!(x, PackageIdentifier).unwrap(),
+                            SVConstructType::Package,
+                        ),
+                        _ => continue,
+                    };
+                    self.register_declaration(&pf.ast, id.clone(), pf.path.clone(), ty);
+                    declarations.push((node.clone(), get_identifier(&pf.ast, id).0));
+                }
+                // look for all instantiations, imports and interface ports. If one of them is
+                // undefined, this recursively attempts to load a library module for it.
+                for (node, parent_name) in declarations {
+                    info!(
+                        "Resolving dependencies of library module `{}`",
+                        &parent_name
+                    );
+                    self.find_and_register_instantiations(&pf.ast, node, &parent_name, files);
+                }
+                // add the parsed file to the vector.
+                files.push(pf);
+            }
+            Err(e) => info!("error loading library: {}", e),
+        }
+    }
+
+    /// Find packages used by a single module or interface only, through a plain `import pkg::*;`
+    /// in its body. The items of these packages are inlined in place of the import and the
+    /// package itself is striped from the sources. Packages imported outside of any declaration
+    /// are kept, since the import would otherwise refer to a package which no longer exists.
+    pub fn flatten_single_use_packages(&mut self, syntax_trees: &[ParsedFile]) -> Result<()> {
+        let mut global_imports = HashSet::new();
+        for pf in syntax_trees {
+            for node in &pf.ast {
+                if let RefNode::DescriptionPackageItem(x) = node {
+                    for item in x {
+                        if let RefNode::PackageImportItem(item) = item {
+                            let id = unwrap_node!(item, SimpleIdentifier, EscapedIdentifier);
+                            global_imports.insert(get_identifier(&pf.ast, id.unwrap()).0);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut candidates: Vec<(String, String)> = self
+            .module_type_map
+            .iter()
+            .filter(|(_, ty)| **ty == SVConstructType::Package)
+            .filter(|(name, _)| !global_imports.contains(*name))
+            .filter_map(|(name, _)| {
+                let mut users = self
+                    .module_graph
+                    .neighbors_directed(self.module_graph_nodes[name], Direction::Incoming);
+                match (users.next(), users.next()) {
+                    (Some(user), None) => Some((name.clone(), self.module_graph[user].clone())),
+                    _ => None,
+                }
+            })
+            .filter(|(_, user)| {
+                matches!(
+                    self.module_type_map.get(user),
+                    Some(SVConstructType::Module) | Some(SVConstructType::Interface)
+                )
+            })
+            .collect();
+        candidates.sort();
+
+        for (package, user) in candidates {
+            let find = |name: &String| {
+                let path = self.module_file_map.get(name)?;
+                let pf = syntax_trees.iter().find(|pf| &pf.path == path)?;
+                Some((pf, find_declaration(&pf.ast, name)?))
+            };
+            let (user_pf, user_decl) = match find(&user) {
+                Some(x) => x,
+                None => continue,
+            };
+            let (package_pf, package_decl) = match find(&package) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            // The import must be the only reference to the package and must not be part of the
+            // header, where the package items could not be inlined.
+            let mut references = 0;
+            let mut import = None;
+            let mut headers = vec![];
+            for node in user_decl {
+                match node {
+                    RefNode::PackageImportItem(x) => {
+                        let id = unwrap_node!(x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                        references += (get_identifier(&user_pf.ast, id).0 == package) as usize;
+                    }
+                    RefNode::PackageScope(x) => {
+                        let id = unwrap_node!(x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                        references += (get_identifier(&user_pf.ast, id).0 == package) as usize;
+                    }
+                    RefNode::ClassScope(x) => {
+                        let id = unwrap_node!(x, SimpleIdentifier, EscapedIdentifier).unwrap();
+                        references += (get_identifier(&user_pf.ast, id).0 == package) as usize;
+                    }
+                    RefNode::PackageImportDeclaration(x) => {
+                        if get_wildcard_import(&user_pf.ast, x).as_ref() == Some(&package) {
+                            import = Some(Locate::try_from(x).unwrap());
+                        }
+                    }
+                    RefNode::ModuleAnsiHeader(x) => headers.push(Locate::try_from(x).unwrap()),
+                    RefNode::ModuleNonansiHeader(x) => headers.push(Locate::try_from(x).unwrap()),
+                    RefNode::InterfaceAnsiHeader(x) => headers.push(Locate::try_from(x).unwrap()),
+                    RefNode::InterfaceNonansiHeader(x) => {
+                        headers.push(Locate::try_from(x).unwrap())
+                    }
+                    _ => (),
+                }
+            }
+            let import = match import {
+                Some(import) if references == 1 => import,
+                _ => continue,
+            };
+            if headers
+                .iter()
+                .any(|h| h.offset <= import.offset && import.offset < h.offset + h.len)
+            {
+                continue;
+            }
+
+            // Collect the package items, with renaming applied.
+            let items: Vec<Locate> = package_decl
+                .clone()
+                .into_iter()
+                .filter_map(|node| match node {
+                    RefNode::PackageItem(x) => Locate::try_from(x).ok(),
+                    _ => None,
+                })
+                .collect();
+            let start = items.iter().map(|l| l.offset).min().unwrap_or(0);
+            let end = items.iter().map(|l| l.offset + l.len).max().unwrap_or(0);
+
+            let mut replace_table = std::mem::take(&mut self.replace_table);
+            for node in package_decl {
+                self.register_usages(&package_pf.ast, &node);
+            }
+            std::mem::swap(&mut replace_table, &mut self.replace_table);
+            replace_table.retain(|(offset, len, _)| start <= *offset && offset + len <= end);
+            // Cut the items out of the source.
+            let source = &package_pf.source;
+            replace_table.push((0, start, "".to_string()));
+            replace_table.push((
+                end.max(start),
+                source.len() - end.max(start),
+                "".to_string(),
+            ));
+            let body = apply_replacements(&package_pf.path, source, replace_table)?;
+
+            info!("Flattening package `{}` into `{}`", package, user);
+            // The package no longer exists in the pickle, hence must not be renamed.
+            self.rename_table.remove(&package);
+            self.flattened_packages.insert(package, body);
+        }
+        Ok(())
+    }
+
+    /// Apply renaming, exclusion and macro/timeunit removal to a parsed file and return the
+    /// resulting source text.
+    pub fn get_replaced_string(
+        &mut self,
+        pf: &ParsedFile,
+        keep: Option<&HashSet<String>>,
+        keep_defines: bool,
+        remove_timeunits: bool,
+    ) -> Result<String> {
+        // For each file, start with a clean replacement table.
+        self.replace_table.clear();
+        // Iterate again and check for usage
+        for node in &pf.ast {
+            self.register_usages(&pf.ast, &node);
+            if let RefNode::ModuleInstantiation(x) = node {
+                if self.module_ports.is_some() {
+                    self.register_dot_star(pf, x)?;
+                }
+            }
+            match node {
+                // Check whether we want to exclude the given module from the file sources.
+                RefNode::ModuleDeclarationAnsi(x) => {
+                    let id = unwrap_node!(x, ModuleIdentifier).unwrap();
+                    let loc = Locate::try_from(x).unwrap();
+                    if !self.register_exclude(
+                        &pf.ast,
+                        id.clone(),
+                        SVConstructType::Module,
+                        loc,
+                        keep,
+                    ) && !self.register_duplicate(&pf.ast, id, &pf.path, loc)
+                    {
+                        self.register_source_annotation(&pf.path, loc);
+                    }
+                }
+                RefNode::ModuleDeclarationNonansi(x) => {
+                    let id = unwrap_node!(x, ModuleIdentifier).unwrap();
+                    let loc = Locate::try_from(x).unwrap();
+                    if !self.register_exclude(
+                        &pf.ast,
+                        id.clone(),
+                        SVConstructType::Module,
+                        loc,
+                        keep,
+                    ) && !self.register_duplicate(&pf.ast, id, &pf.path, loc)
+                    {
+                        self.register_source_annotation(&pf.path, loc);
+                    }
+                }
+                RefNode::InterfaceDeclaration(x) if !is_extern_interface(x) => {
+                    let id = unwrap_node!(x, InterfaceIdentifier).unwrap();
+                    let loc = Locate::try_from(x).unwrap();
+                    if !self.register_exclude(
+                        &pf.ast,
+                        id.clone(),
+                        SVConstructType::Interface,
+                        loc,
+                        keep,
+                    ) && !self.register_duplicate(&pf.ast, id, &pf.path, loc)
+                    {
+                        self.register_source_annotation(&pf.path, loc);
+                    }
+                }
+                RefNode::PackageDeclaration(x) => {
+                    let id = unwrap_node!(x, PackageIdentifier).unwrap();
+                    let loc = Locate::try_from(x).unwrap();
+                    if !self.register_exclude(
+                        &pf.ast,
+                        id.clone(),
+                        SVConstructType::Package,
+                        loc,
+                        keep,
+                    ) && !self.register_duplicate(&pf.ast, id.clone(), &pf.path, loc)
+                        && !self.register_flattened_package(&pf.ast, id, loc)
+                        && !self.register_blank_package(&pf.ast, x, loc)
+                    {
+                        self.register_source_annotation(&pf.path, loc);
+                    }
+                }
+                RefNode::ConfigDeclaration(x) => {
+                    let id = unwrap_node!(x, ConfigIdentifier).unwrap();
+                    let loc = Locate::try_from(x).unwrap();
+                    if !self.register_exclude(
+                        &pf.ast,
+                        id.clone(),
+                        SVConstructType::Config,
+                        loc,
+                        keep,
+                    ) && !self.register_duplicate(&pf.ast, id, &pf.path, loc)
+                    {
+                        self.register_source_annotation(&pf.path, loc);
+                    }
+                }
+                // Instance names, if requested. Interface instances are referenced like signals and
+                // keep their name.
+                RefNode::ModuleInstantiation(x) if self.rename_instances => {
+                    let (module_name, _) = get_identifier(&pf.ast, (&x.nodes.0).into());
+                    if self.module_type_map.get(&module_name) == Some(&SVConstructType::Interface) {
+                        continue;
+                    }
+                    for node in x {
+                        if let RefNode::NameOfInstance(name) = node {
+                            let id = unwrap_node!(name, SimpleIdentifier, EscapedIdentifier);
+                            let (inst_name, loc) = get_identifier(&pf.ast, id.unwrap());
+                            self.replace_table.push((
+                                loc.offset,
+                                loc.len,
+                                self.renamed(&inst_name),
+                            ));
+                        }
+                    }
+                }
+                // Global package imports which were already emitted, if requested.
+                RefNode::DescriptionPackageItem(x) if self.dedup_global_imports => {
+                    let loc = Locate::try_from(x).unwrap();
+                    // Only consider items which are an import themselves, not e.g. functions
+                    // containing one.
+                    let import = match unwrap_node!(x, PackageImportDeclaration) {
+                        Some(RefNode::PackageImportDeclaration(import)) => import,
+                        _ => continue,
+                    };
+                    let import_loc = Locate::try_from(import).unwrap();
+                    if import_loc.offset != loc.offset || import_loc.len != loc.len {
+                        continue;
+                    }
+                    let text = pf.source[loc.offset..loc.offset + loc.len]
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    if !self.global_imports.insert(text) {
+                        debug!("Remove repeated global import: {:?}", loc);
+                        self.replace_table
+                            .push((loc.offset, loc.len, "".to_string()));
+                    }
+                }
+                // Inline flattened packages in place of their import.
+                RefNode::PackageImportDeclaration(x) => {
+                    if let Some(body) = get_wildcard_import(&pf.ast, x)
+                        .and_then(|name| self.flattened_packages.get(&name))
+                    {
+                        let loc = Locate::try_from(x).unwrap();
+                        self.replace_table
+                            .push((loc.offset, loc.len, format!("{}\n", body)));
+                    }
+                }
+                // Implicit named port connections, an explicit `.clk()` remains unconnected.
+                RefNode::NamedPortConnectionIdentifier(x)
+                    if self.expand_implicit_ports && x.nodes.3.is_none() =>
+                {
+                    let (port, loc) = get_identifier(&pf.ast, (&x.nodes.2).into());
+                    self.replace_table
+                        .push((loc.offset, loc.len, format!("{0}({0})", port)));
+                }
+                RefNode::DpiImportExport(x) if self.strip_dpi => {
+                    let loc = Locate::try_from(x).unwrap();
+                    self.replace_table
+                        .push((loc.offset, loc.len, "".to_string()));
+                }
+                RefNode::TimeunitsDeclaration(x) => {
+                    let loc = Locate::try_from(x).unwrap();
+                    if remove_timeunits {
+                        self.replace_table
+                            .push((loc.offset, loc.len, "".to_string()));
+                    }
+                }
+                RefNode::TimescaleCompilerDirective(x) => {
+                    let loc = Locate::try_from(x).unwrap();
+                    if remove_timeunits {
+                        self.replace_table
+                            .push((loc.offset, loc.len, "".to_string()));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // Find macros to be removed
+        let mut new_replace_table = Vec::new();
+
+        if !keep_defines {
+            for node in &pf.ast {
+                if let RefNode::TextMacroDefinition(x) = node {
+                    let loc = Locate::try_from(x).unwrap();
+                    new_replace_table.push((loc.offset, loc.len, "".to_string()));
+                }
+            }
+        }
+
+        new_replace_table.extend(pf.stripped_comments.iter().cloned());
+        new_replace_table.append(&mut self.replace_table);
+
+        apply_replacements(&pf.path, &pf.source, new_replace_table)
     }
 
-    pub fn register_instantiation(&mut self, syntax_tree: &SyntaxTree, id: RefNode) {
-        let (inst_name, _) = get_identifier(syntax_tree, id.clone());
-        self.inst_table.insert(inst_name.clone());
+    /// Write one pickle per top module into `out_dir`.
+    ///
+    /// Top modules are all nodes of the module graph without any incoming edge. Each pickle is
+    /// named `<top>.sv` and only contains the declarations reachable from its top, ordered such
+    /// that declarations precede their usage.
+    pub fn get_split_pickle(
+        &mut self,
+        syntax_trees: &[ParsedFile],
+        out_dir: &Path,
+        keep_defines: bool,
+        remove_timeunits: bool,
+    ) -> Result<()> {
+        std::fs::create_dir_all(out_dir).with_context(|| {
+            format!("Failed to create output directory `{}`", out_dir.display())
+        })?;
+
+        // Edges point from the instantiating to the instantiated module, so dependencies come
+        // last in topological order.
+        let order = toposort(&self.module_graph, None).map_err(|cycle| {
+            anyhow!(
+                "Cyclic dependency involving `{}`",
+                self.module_graph[cycle.node_id()]
+            )
+        })?;
+
+        let tops: Vec<NodeIndex> = self
+            .module_graph
+            .node_indices()
+            .filter(|n| {
+                self.module_graph
+                    .neighbors_directed(*n, Direction::Incoming)
+                    .next()
+                    .is_none()
+            })
+            .collect();
+
+        for top in tops {
+            let top_name = self.module_graph[top].clone();
+
+            let mut reachable = HashSet::new();
+            let mut dfs = Dfs::new(&self.module_graph, top);
+            while let Some(n) = dfs.next(&self.module_graph) {
+                reachable.insert(self.module_graph[n].clone());
+            }
+
+            // Collect the declaring files, dependencies first.
+            let mut files: Vec<String> = vec![];
+            for n in order.iter().rev() {
+                let name = &self.module_graph[*n];
+                if !reachable.contains(name) {
+                    continue;
+                }
+                if let Some(file) = self.module_file_map.get(name) {
+                    if !files.contains(file) {
+                        files.push(file.clone());
+                    }
+                }
+            }
+            match self.pickle_format {
+                PickleFormat::Classic => {
+                    files.sort_by_key(|file| syntax_trees.iter().position(|pf| &pf.path == file))
+                }
+                PickleFormat::Alpha => {
+                    files.sort_by_cached_key(|file| self.first_declaration(file))
+                }
+                PickleFormat::Graph => (),
+            }
 
-        if let Some((parent_name, _)) = get_calling_module(syntax_tree, id) {
-            self.add_dependency_relation(&inst_name, &parent_name);
+            let path = out_dir.join(format!("{}.sv", top_name));
+            info!("Writing pickle for `{}` to `{}`", top_name, path.display());
+            let mut out = BufWriter::new(
+                File::create(&path)
+                    .with_context(|| format!("Failed to create `{}`", path.display()))?,
+            );
+            self.write_header(&mut out)?;
+            self.global_imports.clear();
+            let mut body = String::new();
+            for file in &files {
+                let pf = match syntax_trees.iter().find(|pf| &pf.path == file) {
+                    Some(pf) if !self.excludes_file(&pf.path) => pf,
+                    _ => continue,
+                };
+                let replaced =
+                    self.get_replaced_string(pf, Some(&reachable), keep_defines, remove_timeunits)?;
+                append_file(&mut body, &replaced);
+            }
+            write!(out, "{}", body)?;
         }
+
+        Ok(())
     }
 
-    pub fn register_instantiation_with_parent(
+    /// The files emitted into the pickle, in emission order. If `files` is given, only these files
+    /// are emitted.
+    pub fn get_emitted_files<'b>(
+        &self,
+        syntax_trees: &'b [ParsedFile],
+        files: Option<&HashSet<String>>,
+    ) -> Vec<&'b ParsedFile> {
+        let order = match self.pickle_format {
+            PickleFormat::Classic => (0..syntax_trees.len()).collect(),
+            PickleFormat::Graph => self.get_emission_order(syntax_trees),
+            PickleFormat::Alpha => {
+                let mut order: Vec<usize> = (0..syntax_trees.len()).collect();
+                order.sort_by_cached_key(|i| self.first_declaration(&syntax_trees[*i].path));
+                order
+            }
+        };
+        order
+            .into_iter()
+            .map(|i| &syntax_trees[i])
+            .filter(|pf| files.map_or(true, |files| files.contains(&pf.path)))
+            .filter(|pf| {
+                let excluded = self.excludes_file(&pf.path);
+                if excluded {
+                    debug!("Exclude file `{}`", pf.path);
+                }
+                !excluded
+            })
+            .collect()
+    }
+
+    /// The alphabetically first name of the declarations in a file after renaming, ignoring excluded
+    /// declarations. Files without any declaration come first.
+    fn first_declaration(&self, file: &str) -> Option<String> {
+        self.module_file_map
+            .iter()
+            .filter(|(name, path)| *path == file && !self.is_excluded(name))
+            .map(|(name, _)| self.rename_table.get(name).unwrap_or(name).clone())
+            .min()
+    }
+
+    /// Order in which to emit the files, such that packages are declared before the files which
+    /// depend on them. Apart from that, the order of the files is retained.
+    pub fn get_emission_order(&self, syntax_trees: &[ParsedFile]) -> Vec<usize> {
+        let file_index: HashMap<&String, usize> = syntax_trees
+            .iter()
+            .enumerate()
+            .map(|(i, pf)| (&pf.path, i))
+            .collect();
+
+        // The files declaring packages used by the declarations in each file.
+        let mut deps: Vec<Vec<usize>> = vec![vec![]; syntax_trees.len()];
+        for (name, file) in &self.module_file_map {
+            let i = match file_index.get(file) {
+                Some(i) => *i,
+                None => continue,
+            };
+            for n in self
+                .module_graph
+                .neighbors_directed(self.module_graph_nodes[name], Direction::Outgoing)
+            {
+                let used = &self.module_graph[n];
+                if self.module_type_map.get(used) != Some(&SVConstructType::Package) {
+                    continue;
+                }
+                if let Some(j) = self
+                    .module_file_map
+                    .get(used)
+                    .and_then(|f| file_index.get(f))
+                {
+                    if *j != i {
+                        deps[i].push(*j);
+                    }
+                }
+            }
+        }
+
+        fn visit(i: usize, deps: &mut [Vec<usize>], state: &mut [u8], order: &mut Vec<usize>) {
+            // 0: unvisited, 1: in progress (cyclic dependencies are ignored), 2: done.
+            if state[i] != 0 {
+                return;
+            }
+            state[i] = 1;
+            let mut file_deps = std::mem::take(&mut deps[i]);
+            file_deps.sort_unstable();
+            for j in file_deps {
+                visit(j, deps, state, order);
+            }
+            state[i] = 2;
+            order.push(i);
+        }
+
+        let mut state = vec![0; syntax_trees.len()];
+        let mut order = Vec::with_capacity(syntax_trees.len());
+        for i in 0..syntax_trees.len() {
+            visit(i, &mut deps, &mut state, &mut order);
+        }
+        order
+    }
+
+    /// Pickle the given files into a single string, starting with the header. If `files` is
+    /// given, only these files are emitted.
+    pub fn get_pickle_string(
         &mut self,
-        syntax_tree: &SyntaxTree,
-        id: RefNode,
-        parent_name: &str,
-    ) {
-        let (inst_name, _) = get_identifier(syntax_tree, id.clone());
-        self.inst_table.insert(inst_name.clone());
+        syntax_trees: &[ParsedFile],
+        files: Option<&HashSet<String>>,
+        keep_defines: bool,
+        remove_timeunits: bool,
+    ) -> Result<String> {
+        let mut pickled = self.get_header();
+        self.global_imports.clear();
+        let mut body = String::new();
+        let emitted = self.get_emitted_files(syntax_trees, files);
+        for pf in &emitted {
+            let replaced = self.get_replaced_string(pf, None, keep_defines, remove_timeunits)?;
+            append_file(&mut body, &replaced);
+        }
+        if self.toc {
+            let header_lines = pickled.lines().count();
+            pickled.push_str(&self.get_toc(&body, header_lines));
+        }
+        pickled.push_str(&body);
+        let emitted: HashSet<&String> = emitted.iter().map(|pf| &pf.path).collect();
+        self.stats = Some(self.get_stats(&emitted, &pickled));
+        Ok(pickled)
+    }
 
-        self.add_dependency_relation(&inst_name, parent_name);
+    /// Statistics of a pickle, given the emitted files.
+    pub fn get_stats(&self, emitted: &HashSet<&String>, pickled: &str) -> PickleStats {
+        let mut stats = PickleStats {
+            lines: pickled.lines().count(),
+            ..Default::default()
+        };
+        for (name, file) in &self.module_file_map {
+            let ty = self.module_type_map[name];
+            if !emitted.contains(file)
+                || self.exclude.contains(name)
+                || self.exclude_patterns.is_match(name)
+                || self.exclude_kinds.contains(&(ty, name.clone()))
+                || self.flattened_packages.contains_key(name)
+            {
+                continue;
+            }
+            match ty {
+                SVConstructType::Module => stats.modules += 1,
+                SVConstructType::Interface => stats.interfaces += 1,
+                SVConstructType::Package => stats.packages += 1,
+                SVConstructType::Config => stats.configs += 1,
+            }
+            if self.rename_table.get(name).map_or(false, |x| x != name) {
+                stats.renames += 1;
+            }
+        }
+        stats
     }
 
-    pub fn add_dependency_relation(&mut self, inst_name: &str, parent_name: &str) {
-        if !self.module_graph_nodes.contains_key(inst_name) {
-            self.module_graph_nodes.insert(
-                inst_name.to_string(),
-                self.module_graph.add_node(inst_name.to_string()),
-            );
+    /// A table of contents listing the declarations in `body` with their line in the pickle, given
+    /// the number of lines preceding the table. Declarations are found by their keyword at the
+    /// start of a line, hence the lines are approximate.
+    pub fn get_toc(&self, body: &str, preceding_lines: usize) -> String {
+        let declared: HashMap<&str, SVConstructType> = self
+            .module_type_map
+            .iter()
+            .map(|(name, ty)| {
+                let name = self.rename_table.get(name).unwrap_or(name);
+                (name.as_str(), *ty)
+            })
+            .collect();
+        let mut entries = vec![];
+        for (i, line) in body.lines().enumerate() {
+            let mut words = line
+                .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .filter(|x| !x.is_empty());
+            if !matches!(
+                line.trim_start().split_whitespace().next(),
+                Some("module" | "macromodule" | "interface" | "package" | "config")
+            ) {
+                continue;
+            }
+            if let Some((name, ty)) = words.find_map(|x| declared.get(x).map(|ty| (x, ty))) {
+                entries.push((ty.to_string(), name, i));
+            }
         }
-        self.module_graph.update_edge(
-            self.module_graph_nodes[parent_name],
-            self.module_graph_nodes[inst_name],
-            (),
+        // The table itself consists of a title, the entries, and a blank line.
+        let first_line = preceding_lines + entries.len() + 3;
+        let mut toc = format!("{}\n", self.comment_style.comment("Contents:"));
+        for (ty, name, i) in entries {
+            let entry = format!("  {} {}: line {}", ty, name, first_line + i);
+            toc.push_str(&format!("{}\n", self.comment_style.comment(&entry)));
+        }
+        toc.push('\n');
+        toc
+    }
+
+    /// The header preceding the pickled sources.
+    pub fn get_header(&self) -> String {
+        let mut header = format_header(
+            self.banner.as_deref(),
+            self.no_header,
+            self.no_timestamp,
+            self.comment_style,
         );
+        if let Some(timescale) = &self.timescale {
+            header.push_str(&format!("`timescale {}\n\n", timescale));
+        }
+        header
     }
 
-    pub fn find_and_register_instantiations(
-        &mut self,
-        syntax_tree: &SyntaxTree,
-        id: RefNode,
-        parent_name: &str,
-        library_files: &mut Vec<ParsedFile>,
-    ) {
-        for node in id {
-            match node {
-                RefNode::ModuleInstantiation(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+    /// Write the header preceding the pickled sources.
+    pub fn write_header(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        write!(out, "{}", self.get_header())
+    }
 
-                    let (inst_name, _) = get_identifier(syntax_tree, id.clone());
-                    if !self.rename_table.contains_key(&inst_name) {
-                        info!("Could not find {}, checking libraries...", &inst_name);
-                        self.load_library_module(&inst_name, library_files);
+    /// Collect the ports of all modules and interfaces, which enables the expansion of `.*` port
+    /// connections. The first of multiple declarations with the same name is used.
+    pub fn collect_ports(&mut self, syntax_trees: &[ParsedFile]) {
+        let mut module_ports = HashMap::new();
+        for pf in syntax_trees {
+            for node in &pf.ast {
+                let id = match node {
+                    RefNode::ModuleDeclarationAnsi(x) => unwrap_node!(x, ModuleIdentifier),
+                    RefNode::ModuleDeclarationNonansi(x) => unwrap_node!(x, ModuleIdentifier),
+                    RefNode::InterfaceDeclaration(x) if !is_extern_interface(x) => {
+                        unwrap_node!(x, InterfaceIdentifier)
                     }
-                }
-                RefNode::PackageImportItem(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+                    _ => continue,
+                };
+                let name = get_identifier(&pf.ast, id.unwrap()).0;
+                module_ports
+                    .entry(name)
+                    .or_insert_with(|| get_ports(&pf.ast, node.clone()));
+            }
+        }
+        self.module_ports = Some(module_ports);
+    }
 
-                    let (inst_name, _) = get_identifier(syntax_tree, id);
-                    if !self.rename_table.contains_key(&inst_name) {
-                        info!("Could not find {}, checking libraries...", &inst_name);
-                        self.load_library_module(&inst_name, library_files);
+    /// Replace the `.*` port connections of an instantiation with a named connection of each port
+    /// not connected explicitly. The instantiated module must be declared.
+    fn register_dot_star(&mut self, pf: &ParsedFile, inst: &ModuleInstantiation) -> Result<()> {
+        let (module_name, _) = get_identifier(&pf.ast, (&inst.nodes.0).into());
+        for node in inst {
+            let instance = match node {
+                RefNode::HierarchicalInstance(x) => x,
+                _ => continue,
+            };
+            let mut connected = HashSet::new();
+            let mut asterisk = None;
+            for node in instance {
+                match node {
+                    RefNode::NamedPortConnectionIdentifier(x) => {
+                        connected.insert(get_identifier(&pf.ast, (&x.nodes.2).into()).0);
                     }
+                    RefNode::NamedPortConnectionAsterisk(x) => asterisk = Some(x.nodes.1.nodes.0),
+                    _ => (),
                 }
-                RefNode::PackageScope(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+            }
+            let loc = match asterisk {
+                Some(loc) => loc,
+                None => continue,
+            };
+            let ports = self
+                .module_ports
+                .as_ref()
+                .and_then(|x| x.get(&module_name))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Cannot expand `.*` at {}:{}, `{}` is not declared",
+                        pf.path,
+                        loc.line,
+                        module_name
+                    )
+                })?;
+            let expansion: Vec<String> = ports
+                .iter()
+                .filter(|port| !connected.contains(*port))
+                .map(|port| format!(".{0}({0})", port))
+                .collect();
+            // Removing the `.*` would leave a dangling comma.
+            if expansion.is_empty() {
+                continue;
+            }
+            debug!("Expand `.*` of `{}`: {:?}", module_name, loc);
+            let expansion = match self.max_line_width {
+                Some(width) => wrap_connections(&pf.source, loc.offset, loc.len, &expansion, width),
+                None => expansion.join(", "),
+            };
+            self.replace_table.push((loc.offset, loc.len, expansion));
+        }
+        Ok(())
+    }
 
-                    let (inst_name, _) = get_identifier(syntax_tree, id);
-                    if !self.rename_table.contains_key(&inst_name) {
-                        info!("Could not find {}, checking libraries...", &inst_name);
-                        self.load_library_module(&inst_name, library_files);
+    /// Warn about calls of functions and tasks whose DPI import is stripped, which are broken in
+    /// the pickle. Returns the offending calls as name, file, and line.
+    pub fn check_dpi_callers(&self, syntax_trees: &[ParsedFile]) -> Vec<(String, String, usize)> {
+        let mut imports = HashSet::new();
+        for pf in syntax_trees {
+            for node in &pf.ast {
+                if let RefNode::DpiImportExport(x) = node {
+                    if !matches!(
+                        x,
+                        sv_parser::DpiImportExport::ImportFunction(_)
+                            | sv_parser::DpiImportExport::ImportTask(_)
+                    ) {
+                        continue;
+                    }
+                    if let Some(id) = unwrap_node!(x, FunctionIdentifier, TaskIdentifier) {
+                        imports.insert(get_identifier(&pf.ast, id).0);
                     }
                 }
-                RefNode::InterfacePortHeader(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
-
-                    let (inst_name, _) = get_identifier(syntax_tree, id);
-                    if !self.rename_table.contains_key(&inst_name) {
-                        info!("Could not find {}, checking libraries...", &inst_name);
-                        self.load_library_module(&inst_name, library_files);
+            }
+        }
+        let mut callers = vec![];
+        if imports.is_empty() {
+            return callers;
+        }
+        for pf in syntax_trees {
+            for node in &pf.ast {
+                if let RefNode::TfCall(x) = node {
+                    let (name, loc) = match unwrap_node!(x, TfIdentifier) {
+                        Some(id) => get_identifier(&pf.ast, id),
+                        None => continue,
+                    };
+                    if imports.contains(&name) {
+                        warn!(
+                            "`{}` is called in {}:{}, but its DPI import is stripped",
+                            name, pf.path, loc.line
+                        );
+                        callers.push((name, pf.path.clone(), loc.line as usize));
                     }
                 }
-                RefNode::ClassScope(x) => {
-                    let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                    self.register_instantiation_with_parent(syntax_tree, id.clone(), parent_name);
+            }
+        }
+        callers
+    }
 
-                    let (inst_name, _) = get_identifier(syntax_tree, id);
-                    if !self.rename_table.contains_key(&inst_name) {
-                        info!("Could not find {}, checking libraries...", &inst_name);
-                        self.load_library_module(&inst_name, library_files);
+    /// Warn about hierarchical references through instances renamed by `rename_instances`, such as
+    /// `u_inst.sig`, a `defparam`, or a bind target. These references keep the original instance
+    /// name and are broken in the pickle. Returns the offending references as instance name, file,
+    /// and line.
+    pub fn check_hierarchical_references(
+        &self,
+        syntax_trees: &[ParsedFile],
+    ) -> Vec<(String, String, usize)> {
+        // Interface instances keep their name.
+        let renamed: HashSet<&String> = self
+            .instances
+            .values()
+            .flatten()
+            .filter(|(_, module)| {
+                self.module_type_map.get(module) != Some(&SVConstructType::Interface)
+            })
+            .map(|(inst, _)| inst)
+            .collect();
+        let mut references = vec![];
+        if renamed.is_empty() {
+            return references;
+        }
+        for pf in syntax_trees {
+            for node in &pf.ast {
+                // The scopes a hierarchical identifier descends through, or the instance targeted
+                // by a bind directive.
+                let ids: Vec<RefNode> = match node {
+                    RefNode::HierarchicalIdentifier(x) => {
+                        x.nodes.1.iter().map(|(id, _, _)| id.into()).collect()
+                    }
+                    RefNode::BindTargetInstance(x) => vec![(&x.nodes.0.nodes.2).into()],
+                    _ => continue,
+                };
+                for id in ids {
+                    let (name, loc) = get_identifier(&pf.ast, id);
+                    if renamed.contains(&name) {
+                        warn!(
+                            "Hierarchical reference to instance `{}` in {}:{} is not renamed",
+                            name, pf.path, loc.line
+                        );
+                        references.push((name, pf.path.clone(), loc.line as usize));
+                        break;
                     }
                 }
-                _ => (),
             }
         }
+        references
     }
 
-    /// Register a usage of the identifier.
-    pub fn register_usage(&mut self, syntax_tree: &SyntaxTree, id: RefNode) {
-        let (inst_name, loc) = get_identifier(syntax_tree, id);
-        let new_name = match self.rename_table.get(&inst_name) {
-            Some(x) => x,
-            None => return,
-        };
-        debug!("Usage `{}`: {:?}", inst_name, loc);
-        self.replace_table
-            .push((loc.offset, loc.len, new_name.clone()));
+    /// Fail if the module graph contains cycles, listing the modules of each cycle.
+    pub fn check_cycles(&self) -> Result<()> {
+        if !is_cyclic_directed(&self.module_graph) {
+            return Ok(());
+        }
+        let mut cycles: Vec<String> = tarjan_scc(&self.module_graph)
+            .into_iter()
+            .filter(|scc| scc.len() > 1 || self.module_graph.contains_edge(scc[0], scc[0]))
+            .map(|scc| {
+                let mut names: Vec<&str> =
+                    scc.iter().map(|n| self.module_graph[*n].as_str()).collect();
+                names.sort_unstable();
+                format!("`{}`", names.join("`, `"))
+            })
+            .collect();
+        cycles.sort();
+        Err(anyhow!("Cyclic module dependencies: {}", cycles.join("; ")))
+    }
+
+    /// Fail if modules are instantiated without being declared, listing them.
+    pub fn check_undefined(&self) -> Result<()> {
+        let undefined = self.get_undefined_modules();
+        if undefined.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!("Undefined modules: `{}`", undefined.join("`, `")))
+    }
+
+    /// Warn about modules instantiated deeper than `max_depth` below a top module. Returns the
+    /// offending modules with their depth and top module, sorted by name.
+    pub fn check_max_depth(&self, max_depth: usize) -> Vec<(String, usize, String)> {
+        let mut deep = vec![];
+        let tops = self.module_graph.node_indices().filter(|n| {
+            self.module_graph
+                .neighbors_directed(*n, Direction::Incoming)
+                .next()
+                .is_none()
+        });
+        for top in tops {
+            for (n, depth) in dijkstra(&self.module_graph, top, None, |_| 1) {
+                if depth > max_depth {
+                    deep.push((
+                        self.module_graph[n].clone(),
+                        depth,
+                        self.module_graph[top].clone(),
+                    ));
+                }
+            }
+        }
+        deep.sort();
+        for (name, depth, top) in &deep {
+            warn!(
+                "`{}` is instantiated at depth {} below `{}`, exceeding {}",
+                name, depth, top, max_depth
+            );
+        }
+        deep
+    }
+
+    /// Instantiated modules which are not declared anywhere, sorted by name.
+    pub fn get_undefined_modules(&self) -> Vec<String> {
+        let mut undef_modules: Vec<String> = self
+            .inst_table
+            .iter()
+            .filter(|name| !self.rename_table.contains_key(*name))
+            .cloned()
+            .collect();
+        undef_modules.sort();
+        undef_modules
+    }
+
+    /// The module graph as JSON, with the type and file of each declared node. Undeclared
+    /// nodes have neither.
+    pub fn get_graph_json(&self) -> String {
+        let nodes = self
+            .module_graph
+            .node_indices()
+            .map(|idx| {
+                let name = &self.module_graph[idx];
+                GraphJsonNode {
+                    name: name.clone(),
+                    ty: self.module_type_map.get(name).map(|ty| ty.to_string()),
+                    file: self.module_file_map.get(name).cloned(),
+                }
+            })
+            .collect();
+        let edges = self
+            .module_graph
+            .raw_edges()
+            .iter()
+            .map(|edge| GraphJsonEdge {
+                from: self.module_graph[edge.source()].clone(),
+                to: self.module_graph[edge.target()].clone(),
+            })
+            .collect();
+        serde_json::to_string_pretty(&GraphJson { nodes, edges }).unwrap()
+    }
+
+    /// Declarations in `file` and the modules not declared in `file` they depend on, both sorted
+    /// by name.
+    pub fn get_file_dependencies(&self, file: &str) -> (Vec<String>, Vec<String>) {
+        let canonical = |path: &str| std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        let file = canonical(file);
+
+        let mut declared: Vec<String> = self
+            .module_file_map
+            .iter()
+            .filter(|(_, path)| canonical(path.as_str()) == file)
+            .map(|(name, _)| name.clone())
+            .collect();
+        declared.sort();
+
+        let mut instantiated: Vec<String> = declared
+            .iter()
+            .flat_map(|name| self.module_graph.neighbors(self.module_graph_nodes[name]))
+            .map(|n| self.module_graph[n].clone())
+            .filter(|name| !declared.contains(name))
+            .collect();
+        instantiated.sort();
+        instantiated.dedup();
+
+        (declared, instantiated)
+    }
+
+    /// The instance hierarchy below `top`, one instance per line with its module, indented by its
+    /// depth. Names are given as emitted into the pickle.
+    pub fn get_hierarchy(&self, top: &str) -> String {
+        fn visit(
+            pickle: &Pickle,
+            module: &str,
+            depth: usize,
+            path: &mut Vec<String>,
+            out: &mut String,
+        ) {
+            // Cyclic instantiations are only expanded once.
+            if path.iter().any(|x| x == module) {
+                return;
+            }
+            path.push(module.to_string());
+            for (inst, child) in pickle.instances.get(module).into_iter().flatten() {
+                let inst_name = match pickle.rename_instances {
+                    true => pickle.renamed(inst),
+                    false => inst.clone(),
+                };
+                let child_name = pickle.rename_table.get(child).unwrap_or(child);
+                out.push_str(&format!(
+                    "{}{} ({})\n",
+                    "    ".repeat(depth),
+                    inst_name,
+                    child_name
+                ));
+                visit(pickle, child, depth + 1, path, out);
+            }
+            path.pop();
+        }
+
+        let mut out = format!("{}\n", self.rename_table.get(top).map_or(top, |x| x));
+        visit(self, top, 1, &mut vec![], &mut out);
+        out
+    }
+
+    /// Collapse the module graph to the top modules, which are not used by any other declaration,
+    /// and their direct children. Only the graph is affected, not the pickle.
+    pub fn collapse_graph_to_roots(&mut self) {
+        let roots: HashSet<NodeIndex> = self
+            .module_graph
+            .node_indices()
+            .filter(|n| {
+                self.module_graph
+                    .neighbors_directed(*n, Direction::Incoming)
+                    .next()
+                    .is_none()
+            })
+            .collect();
+        let mut keep = roots.clone();
+        for n in &roots {
+            keep.extend(
+                self.module_graph
+                    .neighbors_directed(*n, Direction::Outgoing),
+            );
+        }
+        // Edges leaving the children would show the deeper hierarchy among them.
+        self.module_graph.retain_edges(|g, e| {
+            g.edge_endpoints(e)
+                .map_or(false, |(source, _)| roots.contains(&source))
+        });
+        self.module_graph.retain_nodes(|_, n| keep.contains(&n));
+        // Removing nodes invalidates the indices of the remaining ones.
+        self.module_graph_nodes = self
+            .module_graph
+            .node_indices()
+            .map(|n| (self.module_graph[n].clone(), n))
+            .collect();
+    }
+
+    /// Walk an instance path such as `top/i_sub/i_leaf` or `top.i_sub.i_leaf`, which starts at a
+    /// module followed by instance names separated by `/` or `.`. Each instance is looked up among
+    /// the instances recorded for the module resolved so far. Returns the modules along the path,
+    /// up to the first instance which is not found, if any. A path naming a declared module, such
+    /// as the escaped identifier `\foo.bar`, is taken as that module instead.
+    fn walk_instance_path<'p>(&self, path: &'p str) -> (Vec<String>, Option<&'p str>) {
+        if self.module_graph_nodes.contains_key(path) {
+            return (vec![path.to_string()], None);
+        }
+        let mut segments = path.split(|c| c == '/' || c == '.');
+        let mut modules = vec![segments.next().unwrap_or_default().to_string()];
+        for inst in segments {
+            let module = self
+                .instances
+                .get(modules.last().unwrap())
+                .and_then(|x| x.iter().find(|(name, _)| name == inst));
+            match module {
+                Some((_, module)) => modules.push(module.clone()),
+                None => return (modules, Some(inst)),
+            }
+        }
+        (modules, None)
+    }
+
+    /// The number of instance paths from `module` down to `target`, counting up to two.
+    fn count_instance_paths(
+        &self,
+        module: &str,
+        target: &str,
+        counts: &mut HashMap<String, usize>,
+    ) -> usize {
+        if module == target {
+            return 1;
+        }
+        if let Some(count) = counts.get(module) {
+            return *count;
+        }
+        // Cyclic instantiations do not add any paths.
+        counts.insert(module.to_string(), 0);
+        let count = self
+            .instances
+            .get(module)
+            .into_iter()
+            .flatten()
+            .map(|(_, child)| self.count_instance_paths(child, target, counts))
+            .sum::<usize>()
+            .min(2);
+        counts.insert(module.to_string(), count);
+        count
     }
 
-    // Check whether a given declaration should be striped from the sources.
-    pub fn register_exclude(&mut self, syntax_tree: &SyntaxTree, id: RefNode, locate: Locate) {
-        let (inst_name, loc) = get_identifier(syntax_tree, id);
-        if self.exclude.contains(&inst_name) {
-            debug!("Exclude `{}`: {:?}", inst_name, loc);
-            self.replace_table
-                .push((locate.offset, locate.len, "".to_string()));
+    /// Resolve a top module given as instance path such as `wrapper/i_dut`, to the module of the
+    /// last instance. If no instances are recorded for a module along the path, the last segment
+    /// of the path is taken as module name instead. A plain module name resolves to itself.
+    pub fn resolve_top(&self, top: &str) -> Result<String> {
+        let (mut modules, missing) = self.walk_instance_path(top);
+        let module = modules.pop().unwrap_or_default();
+        match missing {
+            None => Ok(module),
+            Some(_) if !self.instances.contains_key(&module) => {
+                let last = top
+                    .rsplit(|c| c == '/' || c == '.')
+                    .next()
+                    .unwrap_or_default();
+                warn!(
+                    "No instances recorded in `{}`, taking `{}` of top `{}` as module name",
+                    module, last, top
+                );
+                Ok(last.to_string())
+            }
+            Some(inst) => Err(anyhow!(
+                "Instance `{}` of top `{}` not found in `{}`",
+                inst,
+                top,
+                module
+            )),
         }
     }
 
-    // Load the module with name 'module_name' and append the resulting ParsedFile to 'files'.
-    // This function may recursively load other modules if the library uses another library module.
-    // If no module is found in the library bundle, this function does nothing.
-    pub fn load_library_module(&mut self, module_name: &str, files: &mut Vec<ParsedFile>) {
-        let rm = self.libs.load_module(module_name, &mut self.used_libs);
-        match rm {
-            Ok(pf) => {
-                // register all declarations from this library file.
-                for node in &pf.ast {
-                    match node {
-                        RefNode::ModuleDeclarationAnsi(x) => {
-                            let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                            self.register_declaration(&pf.ast, id, pf.path.clone());
-                        }
-                        RefNode::ModuleDeclarationNonansi(x) => {
-                            let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                            self.register_declaration(&pf.ast, id, pf.path.clone());
-                        }
-                        _ => (),
-                    }
-                }
-                // look for all module instantiations
-                for node in &pf.ast {
-                    if let RefNode::ModuleInstantiation(x) = node {
-                        let id = unwrap_node!(x, SimpleIdentifier).unwrap();
-                        self.register_instantiation(&pf.ast, id.clone());
-
-                        // if this module is undefined, recursively attempt to load a library
-                        // module for it.
-                        let (inst_name, _) = get_identifier(&pf.ast, id);
-                        info!(
-                            "Instantiation `{}` in library module `{}`",
-                            &inst_name, &module_name
-                        );
-                        if !self.rename_table.contains_key(&inst_name) {
-                            info!("load library module {}", &inst_name);
-                            self.load_library_module(&inst_name, files);
-                        }
-                    }
-                }
-                // add the parsed file to the vector.
-                files.push(pf);
+    /// Cut the dependency along an instance path such as `top.i_sub.i_leaf` or `top/i_sub/i_leaf`,
+    /// starting at a module and followed by instance names. Pruning then drops the subtree of the
+    /// last instance. The dependency is only cut if the module of the instance is not reachable
+    /// from `top` along any other instance path, since the cut affects all of them.
+    pub fn exclude_path(&mut self, top: &str, path: &str) -> Result<()> {
+        let (mut modules, missing) = self.walk_instance_path(path);
+        if let Some(inst) = missing {
+            return Err(anyhow!(
+                "Instance `{}` of path `{}` not found in `{}`",
+                inst,
+                path,
+                modules.last().unwrap()
+            ));
+        }
+        let (child, parent) = match (modules.pop(), modules.pop()) {
+            (Some(child), Some(parent)) => (child, parent),
+            _ => return Err(anyhow!("Path `{}` does not name an instance", path)),
+        };
+        if self.count_instance_paths(top, &child, &mut HashMap::new()) > 1 {
+            warn!(
+                "Not excluding `{}`, `{}` is instantiated along other paths below `{}` as well",
+                path, child, top
+            );
+            return Ok(());
+        }
+        if let (Some(a), Some(b)) = (
+            self.module_graph_nodes.get(&parent),
+            self.module_graph_nodes.get(&child),
+        ) {
+            while let Some(edge) = self.module_graph.find_edge(*a, *b) {
+                self.module_graph.remove_edge(edge);
             }
-            Err(e) => info!("error loading library: {}", e),
         }
+        Ok(())
     }
 
     pub fn prune_graph(&mut self, top_module: &str) -> Result<()> {
@@ -758,20 +3302,82 @@ impl<'a> Pickle<'a> {
             |_| 1,
         );
 
-        self.module_graph
-            .retain_nodes(|_, n| test_weights.contains_key(&n));
-        self.module_graph_nodes
-            .retain(|_, v| test_weights.contains_key(v));
+        self.retain_graph_nodes(|n| test_weights.contains_key(&n));
+
+        Ok(())
+    }
+
+    /// Whether a declaration is excluded by name, pattern, or kind.
+    fn is_excluded(&self, name: &str) -> bool {
+        self.exclude.iter().any(|x| *x == name)
+            || self.exclude_patterns.is_match(name)
+            || self.module_type_map.get(name).map_or(false, |ty| {
+                self.exclude_kinds.contains(&(*ty, name.to_string()))
+            })
+    }
+
+    /// Remove the excluded declarations from the graph, along with the declarations which are
+    /// only reachable through them.
+    pub fn exclude_prune(&mut self) {
+        let excluded: HashSet<NodeIndex> = self
+            .module_graph_nodes
+            .iter()
+            .filter(|(name, _)| self.is_excluded(name))
+            .map(|(_, n)| *n)
+            .collect();
+
+        // Everything below an excluded declaration is a candidate for removal.
+        let mut candidates = excluded.clone();
+        for n in &excluded {
+            let mut dfs = Dfs::new(&self.module_graph, *n);
+            while let Some(x) = dfs.next(&self.module_graph) {
+                candidates.insert(x);
+            }
+        }
+
+        // Candidates survive if they are still used by a declaration outside the excluded
+        // subtrees, without passing through an excluded declaration.
+        let mut keep = HashSet::new();
+        let mut stack: Vec<NodeIndex> = self
+            .module_graph
+            .node_indices()
+            .filter(|n| !candidates.contains(n))
+            .collect();
+        while let Some(n) = stack.pop() {
+            if excluded.contains(&n) || !keep.insert(n) {
+                continue;
+            }
+            stack.extend(self.module_graph.neighbors_directed(n, Direction::Outgoing));
+        }
+
+        for n in candidates.iter().filter(|n| !keep.contains(n)) {
+            debug!("Prune `{}`", self.module_graph[*n]);
+        }
+        self.retain_graph_nodes(|n| keep.contains(&n));
+    }
+
+    /// Retain only the graph nodes matching `f`, and forget about the declarations of all others.
+    fn retain_graph_nodes(&mut self, f: impl Fn(NodeIndex) -> bool) {
+        self.module_graph.retain_nodes(|_, n| f(n));
+        // Removing nodes invalidates the indices of the remaining ones.
+        self.module_graph_nodes = self
+            .module_graph
+            .node_indices()
+            .map(|n| (self.module_graph[n].clone(), n))
+            .collect();
 
         let test_keys = self.module_graph_nodes.clone();
         self.module_file_map
             .retain(|k, _| test_keys.contains_key(k));
+        self.module_type_map
+            .retain(|k, _| test_keys.contains_key(k));
+        self.module_loc_map.retain(|k, _| test_keys.contains_key(k));
+        self.module_origin_map
+            .retain(|k, _| test_keys.contains_key(k));
 
         self.inst_table.retain(|k| test_keys.contains_key(k));
 
         self.rename_table.retain(|k, _| test_keys.contains_key(k));
-
-        Ok(())
     }
 }
 
@@ -811,20 +3417,126 @@ pub fn parse_file(
     bundle_include_dirs: &[&Path],
     bundle_defines: &HashMap<String, Option<Define>>,
     strip_comments: bool,
+    keep_pragmas: &[String],
+    encoding: &'static Encoding,
+    collect_conditionals: bool,
+    collect_line_marks: bool,
 ) -> Result<ParsedFile> {
     info!("{:?}", filename);
 
-    // Preprocess the verilog files.
+    // The preprocessor reads the file itself, hence compressed files and files in another encoding
+    // are converted into a temporary UTF-8 file first, which is removed once the file is parsed.
+    let text = read_source(filename, encoding)?;
+    let converted = match filename.ends_with(".gz") || encoding != UTF_8 {
+        true => Some(TempSource::new(filename, &text)?),
+        false => None,
+    };
+    let pp_filename = converted
+        .as_ref()
+        .map_or(Path::new(filename), |x| x.0.as_path());
+
+    // Preprocess the verilog files. The preprocessor cannot tell comments carrying pragmas apart,
+    // hence it keeps all comments if pragmas are retained and the others are stripped below.
     let pp = preprocess(
-        filename,
+        pp_filename,
         bundle_defines,
         bundle_include_dirs,
-        strip_comments,
+        strip_comments && keep_pragmas.is_empty(),
         false,
     )
     .with_context(|| format!("Failed to preprocess `{}`", filename))?;
 
     let buffer = pp.0.text().to_string();
+
+    // Note where the text of the file and its headers ended up in the output, in order to tell
+    // which branches of their conditionals were taken. The offsets are keyed by the canonical path
+    // of the file, also where the preprocessor read a converted copy of it.
+    let canonical = |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+    let mut emitted: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+    if collect_conditionals || text.contains("`include") {
+        let mut paths: HashMap<&Path, PathBuf> = HashMap::new();
+        let mut after_space = true;
+        for (pos, c) in buffer.char_indices() {
+            if after_space && !c.is_whitespace() {
+                if let Some((path, offset)) = pp.0.origin(pos) {
+                    let path = match path.as_path() == pp_filename {
+                        true => Path::new(filename),
+                        false => path.as_path(),
+                    };
+                    let path = paths.entry(path).or_insert_with(|| canonical(path));
+                    emitted.entry(path.clone()).or_default().push(offset);
+                }
+            }
+            after_space = c.is_whitespace();
+        }
+        emitted.values_mut().for_each(|x| x.sort_unstable());
+    }
+
+    // Collect the files included by the file and its headers, in order of appearance. The include
+    // directives are resolved, since headers which only contain defines leave no text in the
+    // preprocessed output.
+    let mut includes: Vec<String> = vec![];
+    find_includes(
+        &text,
+        Path::new(filename),
+        bundle_include_dirs,
+        &pp.1,
+        &emitted,
+        &mut includes,
+    );
+
+    let conditionals = collect_conditionals.then(|| {
+        let emitted = emitted
+            .get(&canonical(Path::new(filename)))
+            .map_or(&[][..], |x| x.as_slice());
+        find_conditionals(&text, emitted).0
+    });
+
+    // Note where each line of the output originates from, whenever it does not continue the
+    // previous line, such as at file boundaries and where an include was expanded.
+    let mut line_marks: Vec<LineMark> = vec![];
+    if collect_line_marks {
+        let text_lines = LineStarts::new(&text);
+        let mut include_lines: HashMap<String, LineStarts> = HashMap::new();
+        let mut last: Option<(String, usize)> = None;
+        let mut line_start = 0;
+        for (index, text_line) in buffer.split('\n').enumerate() {
+            let origin = text_line
+                .find(|c: char| !c.is_whitespace())
+                .and_then(|x| pp.0.origin(line_start + x));
+            line_start += text_line.len() + 1;
+            let (path, offset) = match origin {
+                Some(x) => x,
+                None => {
+                    if let Some(last) = &mut last {
+                        last.1 += 1;
+                    }
+                    continue;
+                }
+            };
+            let path = path.to_string_lossy().into_owned();
+            let (path, line) = if path == filename || Path::new(&path) == pp_filename {
+                (String::from(filename), text_lines.line_of(offset))
+            } else {
+                let line = include_lines
+                    .entry(path.clone())
+                    .or_insert_with(|| {
+                        LineStarts::new(&std::fs::read_to_string(&path).unwrap_or_default())
+                    })
+                    .line_of(offset);
+                (path, line)
+            };
+            if last.as_ref().map(|(p, l)| (p, l + 1)) != Some((&path, line)) {
+                line_marks.push(LineMark {
+                    index,
+                    path: path.clone(),
+                    line,
+                });
+            }
+            last = Some((path, line));
+        }
+    }
+
     let syntax_tree = parse_sv_pp(pp.0, pp.1, false).or_else(|err| -> Result<_> {
         let printer = Arc::new(Mutex::new(printer::Printer::new()));
         let printer = &mut *printer.lock().unwrap();
@@ -832,14 +3544,607 @@ pub fn parse_file(
         Err(Error::new(err))
     })?;
 
+    // Strip the comments which do not start with one of the pragma prefixes. Line breaks within
+    // block comments remain, such that the lines of the source stay in place.
+    let mut stripped_comments = vec![];
+    if strip_comments && !keep_pragmas.is_empty() {
+        for node in &syntax_tree.0 {
+            if let RefNode::Comment(x) = node {
+                let comment = syntax_tree.0.get_str(&x.nodes.0).unwrap_or("");
+                let text = comment
+                    .trim_start_matches(|c| c == '/' || c == '*')
+                    .trim_start();
+                if !keep_pragmas.iter().any(|p| text.starts_with(p.as_str())) {
+                    stripped_comments.push((
+                        x.nodes.0.offset,
+                        x.nodes.0.len,
+                        "\n".repeat(comment.matches('\n').count()),
+                    ));
+                }
+            }
+        }
+    }
+
     Ok(ParsedFile {
         path: String::from(filename),
         source: buffer,
         ast: syntax_tree.0,
         defines: syntax_tree.1,
+        includes,
+        conditionals,
+        line_marks,
+        stripped_comments,
     })
 }
 
+/// Resolve the `include directives of the text of `path` the way the preprocessor does, first
+/// relative to the working directory and then in the include directories, and recurse into the
+/// resolved headers. Directives in comments are skipped, as are directives in branches of
+/// conditionals which were not taken, as told by `emitted`, the sorted offsets of the text of each
+/// file in the preprocessed output. Where it cannot be told whether a branch was taken, its
+/// directives are resolved, hence the includes may contain more files than actually included.
+/// File names given by a macro are resolved with the `defines` at the end of preprocessing.
+/// Directives which cannot be resolved are skipped.
+fn find_includes(
+    text: &str,
+    path: &Path,
+    include_dirs: &[&Path],
+    defines: &Defines,
+    emitted: &HashMap<PathBuf, Vec<usize>>,
+    includes: &mut Vec<String>,
+) {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+    let offsets = emitted.get(&path).map_or(&[][..], |x| x.as_slice());
+    for offset in find_conditionals(text, offsets).1 {
+        let rest = text[offset + "`include".len()..].trim_start();
+        let name = match rest.chars().next() {
+            Some('"') => rest[1..].split('"').next(),
+            Some('<') => rest[1..].split('>').next(),
+            Some('`') => {
+                let macro_name = rest[1..]
+                    .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+                    .next()
+                    .unwrap_or("");
+                defines
+                    .get(macro_name)
+                    .and_then(|x| x.as_ref())
+                    .and_then(|x| x.text.as_ref())
+                    .map(|x| x.text.trim().trim_matches('"'))
+            }
+            _ => None,
+        };
+        let name = match name {
+            Some(x) if !x.contains('\n') => x,
+            _ => continue,
+        };
+        let resolved = if Path::new(name).is_file() {
+            PathBuf::from(name)
+        } else {
+            match include_dirs
+                .iter()
+                .map(|dir| dir.join(name))
+                .find(|x| x.is_file())
+            {
+                Some(x) => x,
+                None => continue,
+            }
+        };
+        let resolved = resolved.to_string_lossy().into_owned();
+        if !includes.contains(&resolved) {
+            let header = std::fs::read_to_string(&resolved).unwrap_or_default();
+            includes.push(resolved.clone());
+            find_includes(
+                &header,
+                Path::new(&resolved),
+                include_dirs,
+                defines,
+                emitted,
+                includes,
+            );
+        }
+    }
+}
+
+/// Add the files to `files` which define macros used by the files in it, including the headers
+/// they include. Only the defining file is known for a macro, hence a file defining a used macro
+/// is kept as a whole, along with the files defining the macros it uses in turn.
+fn keep_macro_files(
+    syntax_trees: &[ParsedFile],
+    encoding: &'static Encoding,
+    files: &mut HashSet<String>,
+) -> Result<()> {
+    let mut macros = vec![];
+    for pf in syntax_trees {
+        let mut defined = HashSet::new();
+        let mut used = HashSet::new();
+        find_macros(&read_source(&pf.path, encoding)?, &mut defined, &mut used);
+        for header in &pf.includes {
+            let text = std::fs::read_to_string(header).unwrap_or_default();
+            find_macros(&text, &mut defined, &mut used);
+        }
+        macros.push((&pf.path, defined, used));
+    }
+
+    let mut used: HashSet<String> = HashSet::new();
+    let mut pending: Vec<_> = macros.iter().filter(|x| files.contains(x.0)).collect();
+    while let Some((_, _, uses)) = pending.pop() {
+        used.extend(uses.iter().cloned());
+        for entry in &macros {
+            if !files.contains(entry.0) && entry.1.iter().any(|x| used.contains(x)) {
+                files.insert(entry.0.clone());
+                pending.push(entry);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collect the macros defined with `` `define `` in a text, and those it uses, either expanded or
+/// tested by a conditional compilation directive.
+fn find_macros(text: &str, defined: &mut HashSet<String>, used: &mut HashSet<String>) {
+    fn ident(s: &str) -> &str {
+        let end = s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+            .unwrap_or(s.len());
+        &s[..end]
+    }
+    for (offset, _) in text.match_indices('`') {
+        let rest = &text[offset + 1..];
+        let name = ident(rest);
+        match name {
+            "define" | "undef" | "ifdef" | "ifndef" | "elsif" => {
+                let arg = ident(rest[name.len()..].trim_start_matches(|c| c == ' ' || c == '\t'));
+                if arg.is_empty() {
+                    continue;
+                }
+                match name {
+                    "define" => defined.insert(arg.to_string()),
+                    _ => used.insert(arg.to_string()),
+                };
+            }
+            ""
+            | "include"
+            | "else"
+            | "endif"
+            | "timescale"
+            | "resetall"
+            | "celldefine"
+            | "endcelldefine"
+            | "default_nettype"
+            | "line"
+            | "pragma"
+            | "begin_keywords"
+            | "end_keywords"
+            | "undefineall"
+            | "unconnected_drive"
+            | "nounconnected_drive"
+            | "__FILE__"
+            | "__LINE__" => {}
+            _ => {
+                used.insert(name.to_string());
+            }
+        }
+    }
+}
+
+/// The offsets at which the lines of a text start, to look up the line of an offset.
+struct LineStarts(Vec<usize>);
+
+impl LineStarts {
+    fn new(text: &str) -> Self {
+        let starts = text.match_indices('\n').map(|(offset, _)| offset + 1);
+        Self(std::iter::once(0).chain(starts).collect())
+    }
+
+    /// The line number of a byte offset into the text, starting at 1.
+    fn line_of(&self, offset: usize) -> usize {
+        self.0.partition_point(|&start| start <= offset)
+    }
+}
+
+/// The origin of a line of preprocessed text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineMark {
+    /// The index of the line in the preprocessed text.
+    pub index: usize,
+    /// The file the line originates from.
+    pub path: String,
+    /// The line in that file, starting at 1.
+    pub line: usize,
+}
+
+/// A conditional compilation directive, and whether its branch was taken.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Conditional {
+    /// The line of the directive.
+    pub line: usize,
+    /// The directive, along with the macro it tests.
+    pub directive: String,
+    /// Whether the branch was taken, unless it could not be determined.
+    pub taken: Option<bool>,
+}
+
+impl Conditional {
+    /// The macro tested by the directive, none for an `` `else ``.
+    pub fn tested_macro(&self) -> Option<&str> {
+        self.directive.split_once(' ').map(|(_, name)| name)
+    }
+}
+
+/// Find the conditional compilation directives of a source and whether their branches were taken.
+/// `emitted` are the sorted offsets of the source text which ended up in the preprocessed output.
+/// A branch was taken if any of its text was emitted. Branches without text of their own, such as
+/// those only containing comments or directives, are deduced from the other branches if possible.
+/// Also returns the offsets of the `` `include `` directives outside of comments, unless their
+/// branch was not taken.
+fn find_conditionals(source: &str, emitted: &[usize]) -> (Vec<Conditional>, Vec<usize>) {
+    struct Branch {
+        cond: usize,
+        start: usize,
+        end: usize,
+        content: bool,
+    }
+    struct Chain {
+        branches: Vec<Branch>,
+        has_else: bool,
+        parent: Option<(usize, usize)>,
+    }
+
+    let bytes = source.as_bytes();
+    let is_ident = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'$';
+    let find = |from: usize, pat: &[u8]| {
+        bytes[from..]
+            .windows(pat.len())
+            .position(|w| w == pat)
+            .map_or(bytes.len(), |x| from + x)
+    };
+
+    let mut conditionals = vec![];
+    let mut includes = vec![];
+    let mut chains: Vec<Chain> = vec![];
+    let mut open: Vec<usize> = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            b'\n' => {
+                line += 1;
+                i += 1;
+            }
+            c if c.is_ascii_whitespace() => i += 1,
+            b'/' if bytes[i..].starts_with(b"//") => i = find(i, b"\n"),
+            b'/' if bytes[i..].starts_with(b"/*") => {
+                i = (find(i + 2, b"*/") + 2).min(bytes.len());
+                line += bytes[start..i].iter().filter(|c| **c == b'\n').count();
+            }
+            b'`' => {
+                i += 1;
+                while i < bytes.len() && is_ident(bytes[i]) {
+                    i += 1;
+                }
+                let name = &source[start + 1..i];
+                match name {
+                    "ifdef" | "ifndef" | "elsif" | "else" => {
+                        let mut directive = format!("`{}", name);
+                        if name != "else" {
+                            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                                line += (bytes[i] == b'\n') as usize;
+                                i += 1;
+                            }
+                            let ident = i;
+                            while i < bytes.len() && is_ident(bytes[i]) {
+                                i += 1;
+                            }
+                            directive = format!("{} {}", directive, &source[ident..i]);
+                        }
+                        let branch = Branch {
+                            cond: conditionals.len(),
+                            start: i,
+                            end: bytes.len(),
+                            content: false,
+                        };
+                        if name.starts_with("if") {
+                            let parent = open.last().map(|c| (*c, chains[*c].branches.len() - 1));
+                            open.push(chains.len());
+                            chains.push(Chain {
+                                branches: vec![branch],
+                                has_else: false,
+                                parent,
+                            });
+                        } else if let Some(chain) = open.last().map(|c| &mut chains[*c]) {
+                            chain.branches.last_mut().unwrap().end = start;
+                            chain.branches.push(branch);
+                            chain.has_else |= name == "else";
+                        } else {
+                            continue;
+                        }
+                        conditionals.push(Conditional {
+                            line,
+                            directive,
+                            taken: None,
+                        });
+                    }
+                    "endif" => {
+                        if let Some(c) = open.pop() {
+                            chains[c].branches.last_mut().unwrap().end = start;
+                        }
+                    }
+                    "define"
+                    | "undef"
+                    | "undefineall"
+                    | "include"
+                    | "timescale"
+                    | "resetall"
+                    | "celldefine"
+                    | "endcelldefine"
+                    | "default_nettype"
+                    | "line"
+                    | "pragma"
+                    | "begin_keywords"
+                    | "end_keywords"
+                    | "unconnected_drive"
+                    | "nounconnected_drive" => {
+                        if name == "include" {
+                            let branch = open.last().map(|c| (*c, chains[*c].branches.len() - 1));
+                            includes.push((start, branch));
+                        }
+                        // Skip the rest of the directive, including continued lines.
+                        while i < bytes.len() && bytes[i] != b'\n' {
+                            if bytes[i..].starts_with(b"\\\n") {
+                                line += 1;
+                                i += 1;
+                            }
+                            i += 1;
+                        }
+                    }
+                    _ => {
+                        // Macros expand to text originating from their definition, hence do not
+                        // tell whether the branch was taken.
+                        if bytes.get(i) == Some(&b'(') {
+                            let mut depth = 0;
+                            while i < bytes.len() {
+                                match bytes[i] {
+                                    b'(' => depth += 1,
+                                    b')' => depth -= 1,
+                                    b'\n' => line += 1,
+                                    _ => (),
+                                }
+                                i += 1;
+                                if depth == 0 {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            c => {
+                if c == b'"' {
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != b'"' && bytes[i] != b'\n' {
+                        i += if bytes[i] == b'\\' { 2 } else { 1 };
+                    }
+                }
+                i += 1;
+                if let Some(chain) = open.last().map(|c| &mut chains[*c]) {
+                    chain.branches.last_mut().unwrap().content = true;
+                }
+            }
+        }
+    }
+
+    // Chains are resolved in order of appearance, such that enclosing branches come first.
+    let mut taken: Vec<Vec<Option<bool>>> = vec![];
+    for chain in &chains {
+        let parent = chain.parent.map(|(c, b)| taken[c][b]);
+        let emitted_in = |b: &Branch| {
+            let k = emitted.partition_point(|x| *x < b.start);
+            k < emitted.len() && emitted[k] < b.end
+        };
+        let empty = chain.branches.iter().filter(|b| !b.content).count();
+        let status = match chain.branches.iter().position(emitted_in) {
+            _ if parent == Some(Some(false)) => vec![Some(false); chain.branches.len()],
+            Some(x) => (0..chain.branches.len()).map(|i| Some(i == x)).collect(),
+            None => chain
+                .branches
+                .iter()
+                .map(|b| match b.content {
+                    true => Some(false),
+                    // With an `else`, one of the branches must have been taken.
+                    false if chain.has_else && empty == 1 && parent != Some(None) => Some(true),
+                    false => None,
+                })
+                .collect(),
+        };
+        taken.push(status);
+    }
+    let includes = includes
+        .into_iter()
+        .filter(|(_, branch)| branch.map_or(true, |(c, b)| taken[c][b] != Some(false)))
+        .map(|(offset, _)| offset)
+        .collect();
+    for (chain, status) in chains.iter().zip(taken) {
+        for (branch, status) in chain.branches.iter().zip(status) {
+            conditionals[branch.cond].taken = status;
+        }
+    }
+    (conditionals, includes)
+}
+
+/// Compile the names containing wildcards (`*`, `?`) into a glob set. Other names are matched
+/// exactly, such that e.g. escaped identifiers with brackets are not taken as patterns.
+fn glob_set(names: &HashSet<&String>) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for name in names.iter().filter(|x| x.contains(['*', '?'])) {
+        match Glob::new(name) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(err) => warn!("Ignoring invalid pattern `{}`: {}", name, err),
+        }
+    }
+    builder.build().unwrap_or_else(|_| GlobSet::empty())
+}
+
+/// Read a source file, decompressing it if it ends in `.gz`, and decode it from `encoding`.
+fn read_source(filename: &str, encoding: &'static Encoding) -> Result<String> {
+    let mut bytes = vec![];
+    let mut file =
+        File::open(filename).with_context(|| format!("Failed to read `{}`", filename))?;
+    match filename.ends_with(".gz") {
+        true => GzDecoder::new(file)
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to decompress `{}`", filename))?,
+        false => file
+            .read_to_end(&mut bytes)
+            .with_context(|| format!("Failed to read `{}`", filename))?,
+    };
+    encoding
+        .decode_without_bom_handling_and_without_replacement(&bytes)
+        .map(|text| text.into_owned())
+        .ok_or_else(|| {
+            anyhow!(
+                "Failed to decode `{}` as {}, select its encoding with `--input-encoding`",
+                filename,
+                encoding.name()
+            )
+        })
+}
+
+/// A temporary file holding the converted contents of a source file, removed when dropped.
+struct TempSource(PathBuf);
+
+impl TempSource {
+    fn new(filename: &str, text: &str) -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        // Keep the name without the `.gz` extension, such that messages remain recognizable.
+        let name = Path::new(filename.trim_end_matches(".gz"))
+            .file_name()
+            .unwrap_or_else(|| OsStr::new("source"));
+        let path = std::env::temp_dir().join(format!(
+            "morty-{}-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            name.to_string_lossy()
+        ));
+        std::fs::write(&path, text)
+            .with_context(|| format!("Failed to write `{}`", path.display()))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempSource {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Append the text of a file to the pickled sources. Leading and trailing blank lines of the file
+/// are dropped, such that files are separated by exactly one blank line regardless of their
+/// formatting, and the pickle ends with a single newline. Files without any text are skipped.
+pub fn append_file(body: &mut String, text: &str) {
+    let first_line = text[..text.len() - text.trim_start().len()]
+        .rfind('\n')
+        .map_or(0, |x| x + 1);
+    let text = text[first_line..].trim_end();
+    if text.is_empty() {
+        return;
+    }
+    if !body.is_empty() {
+        body.push('\n');
+    }
+    body.push_str(text);
+    body.push('\n');
+}
+
+/// Join the connections replacing the `.*` at `offset` of `source`, wrapped into lines of at most
+/// `width` columns. Continuation lines are indented to the column of the `.*`, and the text
+/// following the `.*` on its line is accounted for. A connection wider than a line gets one of its
+/// own.
+fn wrap_connections(
+    source: &str,
+    offset: usize,
+    len: usize,
+    connections: &[String],
+    width: usize,
+) -> String {
+    let line_start = source[..offset].rfind('\n').map_or(0, |x| x + 1);
+    let line_end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |x| offset + x);
+    let indent: String = source[line_start..offset]
+        .chars()
+        .map(|c| if c.is_whitespace() { c } else { ' ' })
+        .collect();
+    // Every line is followed by a comma, the last one by the rest of the source line.
+    let reserve = source[offset + len..line_end].chars().count().max(1);
+
+    let mut result = String::new();
+    let mut column = indent.chars().count();
+    for (i, connection) in connections.iter().enumerate() {
+        let connection_width = connection.chars().count();
+        if i > 0 {
+            if column + 2 + connection_width + reserve > width {
+                result.push_str(",\n");
+                result.push_str(&indent);
+                column = indent.chars().count();
+            } else {
+                result.push_str(", ");
+                column += 2;
+            }
+        }
+        result.push_str(connection);
+        column += connection_width;
+    }
+    result
+}
+
+/// Apply a table of `(offset, len, replacement)` to the source of a file. Replacements contained in
+/// another one, such as a rename within a stripped declaration, are dropped. Partially overlapping
+/// replacements are an error.
+pub fn apply_replacements(
+    path: &str,
+    source: &str,
+    mut replace_table: Vec<(usize, usize, String)>,
+) -> Result<String> {
+    // Of the replacements at the same offset, the longest one comes first such that the others
+    // are contained in it. Insertions precede it nonetheless, since they are not contained.
+    replace_table.sort_by_key(|(offset, len, _)| (*offset, *len != 0, Reverse(*len)));
+    debug!("Replace Table: {:?}", replace_table);
+
+    let span = |offset: usize, len: usize| {
+        let line = source[..offset].matches('\n').count() + 1;
+        format!("{}:{} ({:?})", path, line, &source[offset..offset + len])
+    };
+
+    let mut result = String::with_capacity(source.len());
+    let mut pos = 0;
+    let mut last: Option<(usize, usize)> = None;
+    for (offset, len, repl) in replace_table.iter() {
+        if let Some((last_offset, last_len)) = last {
+            if *offset < last_offset + last_len {
+                if offset + len <= last_offset + last_len {
+                    trace!("Dropping contained replacement: {},{}", offset, len);
+                    continue;
+                }
+                return Err(anyhow!(
+                    "Overlapping replacements at {} and {}",
+                    span(last_offset, last_len),
+                    span(*offset, *len)
+                ));
+            }
+        }
+        trace!("Replacing: {},{}, {}", offset, len, repl);
+        result.push_str(&source[pos..*offset]);
+        result.push_str(repl);
+        pos = offset + len;
+        last = Some((*offset, *len));
+    }
+    result.push_str(&source[pos..]);
+    Ok(result)
+}
+
 pub fn get_identifier(st: &SyntaxTree, node: RefNode) -> (String, Locate) {
     // unwrap_node! can take multiple types
     match unwrap_node!(node, SimpleIdentifier, EscapedIdentifier) {
@@ -854,97 +4159,132 @@ pub fn get_identifier(st: &SyntaxTree, node: RefNode) -> (String, Locate) {
     }
 }
 
-pub fn get_calling_module(st: &SyntaxTree, node: RefNode) -> Option<(String, Locate)> {
-    let unwrapped_node = unwrap_node!(node.clone(), SimpleIdentifier, EscapedIdentifier).unwrap();
-    // let (_, loc0) = get_identifier(st, unwrapped_node.clone());
-
-    // TODO MICHAERO: THIS IS SUUUPER SLOW!!! Especially for packages that have many sub packages...
-
-    // unwrap_node! can take multiple types
-    for st_node in st {
-        match st_node {
-            // Module declarations.
-            RefNode::ModuleDeclarationAnsi(x) => {
-                // unwrap_node! gets the nearest ModuleIdentifier from x
-                let id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                // pickle.register_declaration(&pf.ast, id);
-                let (module_name, module_loc) = get_identifier(st, id);
-                // println!("module_name: {:?}", module_name);
-                // println!("{:?}", x.nodes);
-                let my_ref_node: RefNode = x.into();
-                if my_ref_node
-                    .into_iter()
-                    .filter_map(|sub_node| {
-                        unwrap_node!(sub_node, SimpleIdentifier, EscapedIdentifier)
-                    })
-                    .any(|id| id == unwrapped_node)
-                {
-                    return Some((module_name, module_loc));
+// The names of the ports of a module or interface declaration, in order of declaration.
+fn get_ports(st: &SyntaxTree, decl: RefNode) -> Vec<String> {
+    let mut ports = vec![];
+    for node in decl {
+        match node {
+            RefNode::AnsiPortDeclaration(x) => {
+                if let Some(id) = unwrap_node!(x, PortIdentifier) {
+                    ports.push(get_identifier(st, id).0);
                 }
             }
-            RefNode::ModuleDeclarationNonansi(x) => {
-                // unwrap_node! gets the nearest ModuleIdentifier from x
-                let id = unwrap_node!(x, ModuleIdentifier).unwrap();
-                // pickle.register_declaration(&pf.ast, id);
-                let (module_name, module_loc) = get_identifier(st, id);
-                // println!("module_name: {:?}", module_name);
-                // println!("{:?}", x.nodes);
-                let my_ref_node: RefNode = x.into();
-                if my_ref_node
-                    .into_iter()
-                    .filter_map(|sub_node| {
-                        unwrap_node!(sub_node, SimpleIdentifier, EscapedIdentifier)
-                    })
-                    .any(|id| id == unwrapped_node)
-                {
-                    return Some((module_name, module_loc));
-                }
-            }
-            // Interface Declaration.
-            RefNode::InterfaceDeclaration(x) => {
-                // unwrap_node! gets the nearest InterfaceIdentifier from x
-                let id = unwrap_node!(x, InterfaceIdentifier).unwrap();
-                let (module_name, module_loc) = get_identifier(st, id);
-                // println!("module_name: {:?}", module_name);
-                // println!("{:?}", x.nodes);
-                let my_ref_node: RefNode = x.into();
-                if my_ref_node
-                    .into_iter()
-                    .filter_map(|sub_node| {
-                        unwrap_node!(sub_node, SimpleIdentifier, EscapedIdentifier)
-                    })
-                    .any(|id| id == unwrapped_node)
-                {
-                    return Some((module_name, module_loc));
+            // Non-ANSI declarations may declare several ports at once.
+            RefNode::PortDeclaration(x) => {
+                let ids: Vec<RefNode> = match unwrap_node!(x, ListOfInterfaceIdentifiers) {
+                    Some(list) => list
+                        .into_iter()
+                        .filter(|n| matches!(n, RefNode::InterfaceIdentifier(_)))
+                        .collect(),
+                    None => x
+                        .into_iter()
+                        .filter(|n| {
+                            matches!(
+                                n,
+                                RefNode::PortIdentifier(_) | RefNode::VariableIdentifier(_)
+                            )
+                        })
+                        .collect(),
+                };
+                for id in ids {
+                    ports.push(get_identifier(st, id).0);
                 }
             }
-            // Package declarations.
-            RefNode::PackageDeclaration(x) => {
-                // unwrap_node! gets the nearest PackageIdentifier from x
-                let id = unwrap_node!(x, PackageIdentifier).unwrap();
-                let (module_name, module_loc) = get_identifier(st, id);
-                // println!("module_name: {:?}", module_name);
-                // println!("{:?}", x.nodes);
-                let my_ref_node: RefNode = x.into();
-                if my_ref_node
-                    .into_iter()
-                    .filter_map(|sub_node| {
-                        unwrap_node!(sub_node, SimpleIdentifier, EscapedIdentifier)
-                    })
-                    .any(|id| id == unwrapped_node)
-                {
-                    return Some((module_name, module_loc));
-                }
+            _ => (),
+        }
+    }
+    ports
+}
+
+// Return the package name if the import is a plain `import pkg::*;`.
+fn get_wildcard_import(st: &SyntaxTree, decl: &PackageImportDeclaration) -> Option<String> {
+    let text: String = st
+        .get_str(decl)?
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    text.strip_prefix("import")?
+        .strip_suffix("::*;")
+        .map(String::from)
+}
+
+/// Whether an interface declaration is an `extern` forward declaration, which only declares the
+/// header of an interface. Such declarations are not registered, their name is renamed as a usage.
+/// Forward declarations of modules have node types of their own and are never registered.
+fn is_extern_interface(x: &InterfaceDeclaration) -> bool {
+    matches!(
+        x,
+        InterfaceDeclaration::ExternNonansi(_) | InterfaceDeclaration::ExternAnsi(_)
+    )
+}
+
+// Find the module, interface, package or config declaration with the given name.
+fn find_declaration<'b>(st: &'b SyntaxTree, name: &str) -> Option<RefNode<'b>> {
+    st.into_iter().find(|node| {
+        let id = match node {
+            RefNode::ModuleDeclarationAnsi(x) => unwrap_node!(*x, ModuleIdentifier),
+            RefNode::ModuleDeclarationNonansi(x) => unwrap_node!(*x, ModuleIdentifier),
+            RefNode::InterfaceDeclaration(x) if !is_extern_interface(x) => {
+                unwrap_node!(*x, InterfaceIdentifier)
             }
+            RefNode::PackageDeclaration(x) => unwrap_node!(*x, PackageIdentifier),
+            RefNode::ConfigDeclaration(x) => unwrap_node!(*x, ConfigIdentifier),
+            _ => None,
+        };
+        id.map_or(false, |id| get_identifier(st, id).0 == name)
+    })
+}
 
-            _ => {}
-        }
+/// Statistics of a pickle.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct PickleStats {
+    pub modules: usize,
+    pub interfaces: usize,
+    pub packages: usize,
+    pub configs: usize,
+    pub lines: usize,
+    pub renames: usize,
+}
+
+impl std::fmt::Display for PickleStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} modules, {} interfaces, {} packages, {} configs, {} lines, {} renames",
+            self.modules, self.interfaces, self.packages, self.configs, self.lines, self.renames
+        )
     }
-    // println!("{}", st);
-    // println!("{:?}", loc0);
-    eprintln!("Possible global package import, not properly parsed! TODO MICHAERO better error reporting to fix issue, link all modules/packages/interfaces in file to the dependency.");
-    // panic!("No calling module found.");
-    None
+}
+
+#[derive(Serialize, Debug)]
+pub struct ParsedFileEntry<'a> {
+    pub file: &'a str,
+    pub include_dirs: &'a [String],
+    pub defines: BTreeMap<&'a String, &'a Option<String>>,
+    // loaded from a library to resolve an undefined module
+    pub library: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GraphJson {
+    // declared and instantiated modules
+    pub nodes: Vec<GraphJsonNode>,
+    // instantiations and imports, from user to used
+    pub edges: Vec<GraphJsonEdge>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GraphJsonNode {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: Option<String>,
+    pub file: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GraphJsonEdge {
+    pub from: String,
+    pub to: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -963,15 +4303,46 @@ pub struct FileBundle {
 
     #[serde(default)]
     pub export_incdirs: HashMap<String, Vec<String>>,
+    /// Directory against which relative include directories are resolved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_dir: Option<String>,
     pub defines: HashMap<String, Option<String>>,
     pub files: Vec<String>,
 }
 
+impl FileBundle {
+    /// Resolve relative include directories against the bundle's base directory, if one is set.
+    /// The base directory is consumed, such that resolving twice has no effect.
+    pub fn resolve_include_dirs(&mut self) {
+        let base = match self.base_dir.take() {
+            Some(base) => PathBuf::from(base),
+            None => return,
+        };
+        let resolve = |dir: &mut String| {
+            if Path::new(dir.as_str()).is_relative() {
+                *dir = base.join(dir.as_str()).to_string_lossy().to_string();
+            }
+        };
+        self.include_dirs.iter_mut().for_each(resolve);
+        self.export_incdirs.values_mut().flatten().for_each(resolve);
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LibraryBundle {
     pub include_dirs: Vec<String>,
     pub defines: HashMap<String, Option<String>>,
     pub files: HashMap<String, PathBuf>,
+    /// The encoding of the library files.
+    #[serde(skip, default = "default_encoding")]
+    pub encoding: &'static Encoding,
+    /// The prefixes of comments retained although comments are stripped from library files.
+    #[serde(skip)]
+    pub keep_pragmas: Vec<String>,
+}
+
+fn default_encoding() -> &'static Encoding {
+    UTF_8
 }
 
 impl LibraryBundle {
@@ -993,8 +4364,18 @@ impl LibraryBundle {
 
         files.push(f.to_string());
 
-        // if so, parse the file and return the result (comments are always stripped).
-        parse_file(&f, &bundle_include_dirs, &bundle_defines, true)
+        // if so, parse the file and return the result (comments are always stripped, except for
+        // pragmas).
+        parse_file(
+            &f,
+            &bundle_include_dirs,
+            &bundle_defines,
+            true,
+            &self.keep_pragmas,
+            self.encoding,
+            false,
+            false,
+        )
     }
 }
 
@@ -1008,6 +4389,17 @@ pub struct ParsedFile {
     pub ast: SyntaxTree,
     /// Internal defines
     pub defines: Defines,
+    /// The files included while preprocessing.
+    pub includes: Vec<String>,
+    /// The conditional compilation directives of the file, and whether their branches were taken.
+    /// Only collected on request.
+    pub conditionals: Option<Vec<Conditional>>,
+    /// The origins of the lines of the preprocessed source, wherever they do not continue the
+    /// previous line. Only collected on request.
+    pub line_marks: Vec<LineMark>,
+    /// Replacements removing the comments which are stripped after preprocessing, in order to
+    /// retain those carrying pragmas.
+    pub stripped_comments: Vec<(usize, usize, String)>,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -1038,3 +4430,39 @@ pub fn print_parse_error(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_replacements_same_offset() -> Result<()> {
+        let source = "module a_mod; endmodule";
+        // The rename is contained in the stripped declaration, regardless of their order.
+        let replace_table = vec![
+            (7, 5, "b_mod".to_string()),
+            (0, 23, "".to_string()),
+            (0, 0, "// from a.sv\n".to_string()),
+        ];
+        assert_eq!(
+            apply_replacements("a.sv", source, replace_table)?,
+            "// from a.sv\n"
+        );
+
+        let replace_table = vec![(7, 1, "b".to_string()), (7, 5, "c_mod".to_string())];
+        assert_eq!(
+            apply_replacements("a.sv", source, replace_table)?,
+            "module c_mod; endmodule"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_starts() {
+        let lines = LineStarts::new("a\nbc\n\nd");
+        let expected = [(0, 1), (1, 1), (2, 2), (4, 2), (5, 3), (6, 4), (7, 4)];
+        for (offset, line) in expected {
+            assert_eq!(lines.line_of(offset), line, "offset {}", offset);
+        }
+    }
+}
@@ -7,8 +7,9 @@
 #[macro_use]
 extern crate log;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::{Arg, ArgAction, Command};
+use encoding_rs::{Encoding, UTF_8};
 use log::LevelFilter;
 use simple_logger::SimpleLogger;
 use std::collections::{HashMap, HashSet};
@@ -33,20 +34,52 @@ fn main() -> Result<()> {
                 .action(ArgAction::Append)
                 .num_args(1),
         )
+        .arg(
+            Arg::new("normalize_case")
+                .long("normalize-case")
+                .value_name("lower|upper")
+                .help("Convert the names of renamed declarations to lower or upper case")
+                .value_parser(["lower", "upper"])
+                .num_args(1),
+        )
         .arg(
             Arg::new("exclude_rename")
                 .short('e')
                 .long("exclude-rename")
                 .value_name("MODULE|INTERFACE|PACKAGE")
-                .help("Add module, interface, package which should not be renamed")
+                .help("Add module, interface, package, config which should not be renamed, may contain `*` and `?` wildcards")
                 .action(ArgAction::Append)
                 .num_args(1),
         )
         .arg(
             Arg::new("exclude")
                 .long("exclude")
-                .value_name("MODULE|INTERFACE|PACKAGE")
-                .help("Do not include module, interface, package in the pickled file list")
+                .value_name("[KIND:]NAME")
+                .help("Do not include module, interface, package, config in the pickled file list, optionally only of one KIND, may contain `*` and `?` wildcards")
+                .action(ArgAction::Append)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("exclude_prune")
+                .long("exclude-prune")
+                .help("Also drop the declarations which are only used by excluded declarations")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("exclude_path")
+                .long("exclude-path")
+                .value_name("TOP.INST")
+                .help("Exclude the subtree of an instance below the top module, given by its path")
+                .action(ArgAction::Append)
+                .num_args(1)
+                .requires("top_module"),
+        )
+        .arg(
+            Arg::new("exclude_file")
+                .long("exclude-file")
+                .value_name("PATH")
+                .help("Exclude all declarations in a file from the pickle")
                 .action(ArgAction::Append)
                 .num_args(1),
         )
@@ -57,6 +90,13 @@ fn main() -> Result<()> {
                 .num_args(0)
                 .help("Sets the level of verbosity"),
         )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .help("Do not print informational messages such as file names to stderr")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("prefix")
                 .short('p')
@@ -73,6 +113,23 @@ fn main() -> Result<()> {
                 .action(ArgAction::Append)
                 .num_args(1),
         )
+        .arg(
+            Arg::new("define_file")
+                .long("define-from-file")
+                .value_name("PATH")
+                .help("Read preprocessor macros from a file of `NAME=VALUE` or `NAME` lines")
+                .action(ArgAction::Append)
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("define_priority")
+                .long("define-priority")
+                .value_name("cli|file")
+                .help("Whether command line defines override defines of manifests and file lists, or vice versa")
+                .value_parser(["cli", "file"])
+                .default_value("cli")
+                .num_args(1),
+        )
         .arg(
             Arg::new("suffix")
                 .short('s')
@@ -88,6 +145,14 @@ fn main() -> Result<()> {
                 .num_args(0)
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("line_directives")
+                .long("line-directives")
+                .help("Map the preprocessed output back to the original files with `line directives")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .requires("preproc"),
+        )
         .arg(
             Arg::new("file_list")
                 .short('f')
@@ -104,6 +169,20 @@ fn main() -> Result<()> {
                 .num_args(1)
                 .action(ArgAction::Append),
         )
+        .arg(
+            Arg::new("flist_relative")
+                .long("flist-relative")
+                .help("Resolve relative paths in file lists relative to the file list")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("input_encoding")
+                .long("input-encoding")
+                .value_name("ENCODING")
+                .help("Decode the source files from ENCODING, such as `latin1`, instead of UTF-8")
+                .num_args(1),
+        )
         .arg(
             Arg::new("strip_comments")
                 .long("strip-comments")
@@ -111,6 +190,282 @@ fn main() -> Result<()> {
                 .num_args(0)
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("keep_comments_in")
+                .long("keep-comments-in")
+                .value_name("PATH")
+                .help("Keep the comments of a file when stripping comments")
+                .action(ArgAction::Append)
+                .num_args(1)
+                .requires("strip_comments"),
+        )
+        .arg(
+            Arg::new("keep_pragma")
+                .long("keep-pragma")
+                .value_name("PREFIX")
+                .help("Keep comments starting with PREFIX when stripping comments, replacing the default prefixes")
+                .action(ArgAction::Append)
+                .num_args(1)
+                .default_values(["synopsys", "synthesis", "pragma", "verilator", "cadence"]),
+        )
+        .arg(
+            Arg::new("annotate_sources")
+                .long("annotate-sources")
+                .help("Annotate each declaration with the file it originates from")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedupe_blank_decls")
+                .long("dedupe-blank-decls")
+                .help("Remove packages without any items from the pickled sources")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("allow_duplicate_modules")
+                .long("allow-duplicate-modules")
+                .help("Keep the first of multiple declarations with the same name instead of failing")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .help("Parse the sources and build the module graph without writing the pickle, failing on cycles")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("error_on_undefined")
+                .long("error-on-undefined")
+                .help("Fail if modules are instantiated without being declared")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("error_on_cyclic")
+                .long("error-on-cyclic")
+                .help("Fail early if modules instantiate each other cyclically")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedup_global_imports")
+                .long("dedup-global-imports")
+                .help("Only emit the first of identical global package imports")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("flatten_single_use_packages")
+                .long("flatten-single-use-packages")
+                .help("Inline packages imported by a single module only into that module")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("emit_timescale")
+                .long("emit-timescale")
+                .value_name("UNIT/PRECISION")
+                .help("Emit a single `timescale directive at the top of the pickle, e.g. 1ns/1ps")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("banner")
+                .long("banner")
+                .value_name("TEXT")
+                .help("Prepend custom text, e.g. a license notice, to the pickle")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("banner_file")
+                .long("banner-file")
+                .value_name("FILE")
+                .help("Prepend the contents of FILE, e.g. a license header, to the pickle")
+                .num_args(1)
+                .conflicts_with("banner"),
+        )
+        .arg(
+            Arg::new("expand_dot_star")
+                .long("expand-dot-star")
+                .help("Expand `.*` port connections into named connections, requires the declarations of all instantiated modules")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("expand_implicit_ports")
+                .long("expand-implicit-ports")
+                .help("Expand implicit named port connections such as `.clk` to `.clk(clk)`")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strip_dpi")
+                .long("strip-dpi")
+                .help("Remove DPI import and export declarations from the pickle")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("toc")
+                .long("toc")
+                .help("Prepend a comment listing the declarations and their lines to the pickle")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no_header")
+                .long("no-header")
+                .visible_alias("no-banner")
+                .help("Omit the `// Compiled by morty` header line")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no_timestamp")
+                .long("no-timestamp")
+                .help("Omit the date from the `// Compiled by morty` header line, for reproducible output")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_depth_warn")
+                .long("max-depth-warn")
+                .value_name("N")
+                .help("Warn about modules instantiated more than N levels below a top module")
+                .num_args(1)
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("parse_stack_size")
+                .long("parse-stack-size")
+                .value_name("MB")
+                .help("Stack size of the parser threads, increase on stack overflows")
+                .num_args(1)
+                .default_value("64")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("baseline")
+                .long("baseline")
+                .value_name("OLD_PICKLE")
+                .help("Only emit declarations which changed with respect to a previous pickle")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("rename_instances")
+                .long("rename-instances")
+                .help("Apply prefix and suffix to module instance names as well")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .help("Parse the generated pickle again and fail if it is invalid")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("pickle_format")
+                .long("pickle-format")
+                .help("Order of the pickled files, `classic` retains the order given")
+                .num_args(1)
+                .value_parser(["classic", "graph"])
+                .default_value("graph"),
+        )
+        .arg(
+            Arg::new("sort_output")
+                .long("sort-output")
+                .help("Order of the pickled files: `topo` moves packages before their users, `alpha` sorts by declaration name, `file` retains the order given")
+                .num_args(1)
+                .value_parser(["topo", "alpha", "file"])
+                .conflicts_with("pickle_format"),
+        )
+        .arg(
+            Arg::new("comment_style")
+                .long("comment-style")
+                .help("Style of the comments added to the pickle")
+                .num_args(1)
+                .value_parser(["line", "block"])
+                .default_value("line"),
+        )
+        .arg(
+            Arg::new("max_line_width")
+                .long("max-line-width")
+                .value_name("N")
+                .help("Wrap the connections of expanded `.*` into lines of at most N columns")
+                .num_args(1)
+                .requires("expand_dot_star")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("dump_defines")
+                .long("dump-defines")
+                .value_name("FILE")
+                .help("Write the defines of each parsed file as JSON to FILE")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("ordered_flist")
+                .long("ordered-flist")
+                .value_name("FILE")
+                .help("Write the pickled source files in emission order to FILE")
+                .num_args(1)
+                .conflicts_with("split_output"),
+        )
+        .arg(
+            Arg::new("ifdef_report")
+                .long("ifdef-report")
+                .value_name("FILE")
+                .help("Write the conditional compilation directives of each file, and whether their branches were taken, as JSON to FILE")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("warn_undefined_defines")
+                .long("warn-undefined-defines")
+                .help("Warn about macros tested by `ifdef, `ifndef, or `elsif, but never defined")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("parsed_file_list")
+                .long("emit-parsed-file-list")
+                .value_name("FILE")
+                .help("Write the parsed files with their include directories and defines as JSON to FILE")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("stats")
+                .long("stats")
+                .help("Print the number of pickled declarations, lines, and renames to stderr")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .conflicts_with("split_output"),
+        )
+        .arg(
+            Arg::new("stats_json")
+                .long("stats-json")
+                .value_name("FILE")
+                .help("Write the number of pickled declarations, lines, and renames as JSON to FILE")
+                .num_args(1)
+                .conflicts_with("split_output"),
+        )
+        .arg(
+            Arg::new("list_includes")
+                .long("list-includes")
+                .value_name("FILE")
+                .help("Write the includes resolved for each parsed file to FILE")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("include_deps")
+                .long("include-deps")
+                .value_name("FILE")
+                .help("Write the headers included by each parsed file to FILE as JSON")
+                .num_args(1),
+        )
         .arg(
             Arg::new("INPUT")
                 .help("The input files to compile")
@@ -125,6 +480,27 @@ fn main() -> Result<()> {
                 .help("Generate documentation in a directory")
                 .num_args(1),
         )
+        .arg(
+            Arg::new("doc_single")
+                .long("doc-single")
+                .value_name("FILE")
+                .help("Generate the documentation as a single, self-contained HTML file")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("doc_json")
+                .long("doc-json")
+                .value_name("FILE")
+                .help("Write the documentation items as JSON to FILE")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("keep_source_comments_in_docs")
+                .long("keep-source-comments-in-docs")
+                .help("Include the source of each module, package and type in the documentation")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("output")
                 .short('o')
@@ -132,6 +508,21 @@ fn main() -> Result<()> {
                 .help("Write output to file")
                 .num_args(1),
         )
+        .arg(
+            Arg::new("split_output")
+                .long("split-output")
+                .value_name("DIR")
+                .help("Write one pickle per top module into a directory")
+                .conflicts_with("output")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("pickle_checksum")
+                .long("pickle-checksum")
+                .value_name("FILE")
+                .help("Write the SHA-256 checksum of the pickle to FILE")
+                .num_args(1),
+        )
         .arg(
             Arg::new("library_file")
                 .long("library-file")
@@ -156,11 +547,48 @@ fn main() -> Result<()> {
                 .help("Output a JSON-encoded source information manifest to FILE")
                 .num_args(1),
         )
+        .arg(
+            Arg::new("keep_only_tops")
+                .long("keep-only-tops")
+                .help("Only list the files declaring the top modules in the manifest")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .requires("manifest"),
+        )
+        .arg(
+            Arg::new("include_only_reachable_incdirs")
+                .long("include-only-reachable-incdirs")
+                .help("Only list the include directories needed by the files in the manifest")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .requires("manifest"),
+        )
+        .arg(
+            Arg::new("undefined_out")
+                .long("undefined-out")
+                .value_name("FILE")
+                .help("Output the list of undefined modules to FILE")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("modules_instantiated_in")
+                .long("modules-instantiated-in")
+                .value_name("FILE")
+                .help("Print the declarations in FILE and the modules they instantiate from elsewhere, instead of the pickle")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("list_modules")
+                .long("list-modules")
+                .help("Print all declared modules, interfaces and packages, instead of the pickle")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("top_module")
                 .long("top")
                 .value_name("TOP_MODULE")
-                .help("Top module, strips all unneeded files. May be incompatible with `--propagate_defines`.")
+                .help("Top module, strips all unneeded files. May be an instance path such as `wrapper/i_dut`.")
                 .num_args(1),
         )
         .arg(
@@ -170,6 +598,42 @@ fn main() -> Result<()> {
                 .help("Output a DOT graph of the parsed modules")
                 .num_args(1),
         )
+        .arg(
+            Arg::new("graph_graphml")
+                .long("graph-graphml")
+                .value_name("FILE")
+                .help("Output a GraphML graph of the parsed modules")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("depfile")
+                .long("depfile")
+                .value_name("FILE")
+                .help("Write a Makefile dependency file listing all sources and included files")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("hierarchy")
+                .long("hierarchy")
+                .value_name("FILE")
+                .help("Write the instance hierarchy below the top module to FILE")
+                .num_args(1)
+                .requires("top_module"),
+        )
+        .arg(
+            Arg::new("graph_root_only")
+                .long("graph-root-only")
+                .help("Only include the top modules and their direct children in the graph outputs")
+                .num_args(0)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("graph_json")
+                .long("graph-json")
+                .value_name("FILE")
+                .help("Output a JSON graph of the parsed modules")
+                .num_args(1),
+        )
         .arg(
             Arg::new("ignore_unparseable")
                 .short('i')
@@ -222,21 +686,36 @@ fn main() -> Result<()> {
         .init()
         .unwrap();
 
+    if let Some(timescale) = matches.get_one::<String>("emit_timescale") {
+        check_timescale(timescale)?;
+    }
+
     let mut file_list = Vec::new();
 
-    // Handle user defines.
-    let defines: HashMap<_, _> = match matches.get_many::<String>("def") {
-        Some(args) => args
-            .map(|x| {
-                let mut iter = x.split('=');
-                (
-                    iter.next().unwrap().to_string(),
-                    iter.next().map(String::from),
-                )
-            })
-            .collect(),
-        None => HashMap::new(),
-    };
+    // Handle user defines. Defines from files come first such that `-D` can override them.
+    let mut defines = HashMap::new();
+    for path in matches
+        .get_many::<String>("define_file")
+        .into_iter()
+        .flatten()
+    {
+        defines.extend(read_defines_file(Path::new(path))?);
+    }
+    if let Some(args) = matches.get_many::<String>("def") {
+        defines.extend(args.map(|x| {
+            let mut iter = x.split('=');
+            (
+                iter.next().unwrap().to_string(),
+                iter.next().map(String::from),
+            )
+        }));
+    }
+
+    // Defines given on the command line (`-D`, `--define-from-file`) override those of manifests
+    // and file lists (`+define+`) unless file defines are prioritized.
+    let cli_wins = matches
+        .get_one::<String>("define_priority")
+        .map_or(true, |x| x == "cli");
 
     // Prepare a list of include paths.
     let include_dirs: Vec<_> = matches
@@ -281,10 +760,18 @@ fn main() -> Result<()> {
         }
     }
 
-    let library_bundle = LibraryBundle {
+    let encoding = match matches.get_one::<String>("input_encoding") {
+        Some(label) => Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| anyhow!("Unknown input encoding `{}`", label))?,
+        None => UTF_8,
+    };
+
+    let mut library_bundle = LibraryBundle {
         include_dirs: include_dirs.clone(),
         defines: defines.clone(),
         files: library_files,
+        encoding,
+        keep_pragmas: vec![],
     };
 
     for path in matches
@@ -304,10 +791,11 @@ fn main() -> Result<()> {
             process::exit(1)
         });
         for fb in &mut u {
+            fb.resolve_include_dirs();
             for (_k, v) in fb.export_incdirs.clone() {
                 fb.include_dirs.extend(v);
             }
-            fb.defines.extend(defines.clone());
+            merge_defines(&mut fb.defines, &defines, cli_wins);
             fb.include_dirs.extend(include_dirs.clone());
         }
         file_list.extend(u);
@@ -319,16 +807,21 @@ fn main() -> Result<()> {
         all_files.extend(file_names.map(|x| x.to_string()).collect::<Vec<_>>());
     }
 
+    let mut visited_flists = HashSet::new();
+    let mut flist_libraries = Vec::new();
     for path in matches.get_many::<String>("flist").into_iter().flatten() {
-        let file = File::open(path).unwrap_or_else(|e| {
-            eprintln!("error opening `{}`: {}", path, e);
-            process::exit(1)
-        });
-        let lines = BufReader::new(file).lines();
-
-        let proper_lines: Vec<String> = lines.filter_map(|x| x.ok()).collect();
+        all_files.extend(read_flist(
+            Path::new(path),
+            matches.get_flag("flist_relative"),
+            &mut visited_flists,
+            &mut flist_libraries,
+        ));
+    }
 
-        all_files.extend(proper_lines);
+    for p in flist_libraries {
+        if let Some(m) = lib_module(&p) {
+            library_bundle.files.insert(m, p);
+        }
     }
 
     let mut stdin_incdirs = include_dirs;
@@ -368,11 +861,15 @@ fn main() -> Result<()> {
         })
         .collect();
 
-    stdin_defines.extend(defines);
+    merge_defines(&mut stdin_defines, &defines, cli_wins);
+
+    // Library files are parsed with the same defines as the files given on the command line.
+    library_bundle.defines = stdin_defines.clone();
 
     file_list.push(FileBundle {
         include_dirs: stdin_incdirs.clone(),
         export_incdirs: HashMap::new(),
+        base_dir: None,
         defines: stdin_defines.clone(),
         files: stdin_files,
     });
@@ -384,19 +881,75 @@ fn main() -> Result<()> {
             .into_iter()
             .flatten(),
     );
-    exclude.extend(matches.get_many::<String>("exclude").into_iter().flatten());
+    // Excludes of the form `module:foo` only apply to declarations of that kind.
+    let mut exclude_kinds = HashSet::new();
+    for name in matches.get_many::<String>("exclude").into_iter().flatten() {
+        match name.split_once(':') {
+            Some((kind, name)) => {
+                exclude_kinds.insert((kind.parse::<SVConstructType>()?, name.to_string()));
+            }
+            None => {
+                exclude.insert(name);
+            }
+        }
+    }
+
+    // Compare files by their canonical path, such that relative and absolute paths match.
+    let exclude_files: HashSet<PathBuf> = matches
+        .get_many::<String>("exclude_file")
+        .into_iter()
+        .flatten()
+        .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.into()))
+        .collect();
 
     let strip_comments = matches.get_flag("strip_comments");
+    let keep_comments_in: HashSet<PathBuf> = matches
+        .get_many::<String>("keep_comments_in")
+        .into_iter()
+        .flatten()
+        .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.into()))
+        .collect();
+    let keep_pragmas: Vec<String> = matches
+        .get_many::<String>("keep_pragma")
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    library_bundle.keep_pragmas = keep_pragmas.clone();
 
     let syntax_trees = build_syntax_tree(
         &file_list,
         strip_comments,
+        &keep_comments_in,
+        &keep_pragmas,
         matches.get_flag("ignore_unparseable"),
         matches.get_flag("propagate_defines"),
         matches.get_flag("sequential"),
+        *matches.get_one::<usize>("parse_stack_size").unwrap(),
+        encoding,
+        matches.get_one::<String>("ifdef_report").is_some()
+            || matches.get_flag("warn_undefined_defines"),
+        matches.get_flag("line_directives"),
     )?;
 
+    if let Some(report_file) = matches.get_one::<String>("ifdef_report") {
+        write_ifdef_report(&syntax_trees, report_file)?;
+    }
+
+    if matches.get_flag("warn_undefined_defines") {
+        check_undefined_defines(&syntax_trees);
+    }
+
     let out = match matches.get_one::<String>("output") {
+        // Queries and dry runs only need the module graph, discard the pickle itself.
+        _ if matches
+            .get_one::<String>("modules_instantiated_in")
+            .is_some()
+            || matches.get_flag("list_modules")
+            || matches.get_flag("dry_run") =>
+        {
+            Box::new(io::sink()) as Box<dyn Write>
+        }
         Some(file) => {
             info!("Setting output to `{}`", file);
             let path = Path::new(file);
@@ -408,37 +961,288 @@ fn main() -> Result<()> {
         None => Box::new(io::stdout()) as Box<dyn Write>,
     };
 
-    // Just preprocess.
+    let banner = match matches.get_one::<String>("banner_file") {
+        Some(file) => Some(
+            std::fs::read_to_string(file)
+                .map_err(|e| anyhow!("could not read banner `{}`: {}", file, e))?,
+        ),
+        None => matches.get_one::<String>("banner").cloned(),
+    };
+    let comment_style = match matches
+        .get_one::<String>("comment_style")
+        .map(|x| x.as_str())
+    {
+        Some("block") => CommentStyle::Block,
+        _ => CommentStyle::Line,
+    };
+
+    // Just preprocess. Each file was preprocessed with the include directories and defines of its
+    // bundle, exactly as for a pickle.
     if matches.get_flag("preproc") {
-        return just_preprocess(syntax_trees, out);
+        return just_preprocess(
+            syntax_trees,
+            out,
+            &format_header(
+                banner.as_deref(),
+                matches.get_flag("no_header"),
+                matches.get_flag("no_timestamp"),
+                comment_style,
+            ),
+            matches.get_flag("quiet"),
+            matches.get_flag("line_directives"),
+        );
     }
 
     info!("Finished reading {} source files.", syntax_trees.len());
 
     // Emit documentation if requested.
-    if let Some(dir) = matches.get_one::<String>("docdir") {
-        info!("Generating documentation in `{}`", dir);
-        return build_doc(syntax_trees, dir);
+    let docdir = matches.get_one::<String>("docdir");
+    let doc_single = matches.get_one::<String>("doc_single");
+    let doc_json = matches.get_one::<String>("doc_json");
+    if docdir.is_some() || doc_single.is_some() || doc_json.is_some() {
+        if let Some(dir) = docdir {
+            info!("Generating documentation in `{}`", dir);
+        }
+        if let Some(file) = doc_single {
+            info!("Generating documentation in `{}`", file);
+        }
+        let omitted = file_list
+            .iter()
+            .flat_map(|bundle| bundle.files.iter())
+            .filter(|file| !syntax_trees.iter().any(|pf| &pf.path == *file))
+            .cloned()
+            .collect();
+        return build_doc(
+            syntax_trees,
+            docdir,
+            doc_single,
+            doc_json,
+            omitted,
+            matches.get_flag("keep_source_comments_in_docs"),
+        );
+    }
+
+    if let Some(defines_file) = matches.get_one::<String>("dump_defines") {
+        write_defines(defines_file, &syntax_trees)?;
+    }
+
+    if let Some(includes_file) = matches.get_one::<String>("list_includes") {
+        write_includes(includes_file, &syntax_trees)?;
     }
 
-    let pickle = do_pickle(
-        matches.get_one::<String>("prefix"),
-        matches.get_one::<String>("suffix"),
+    if let Some(include_deps_file) = matches.get_one::<String>("include_deps") {
+        write_include_deps(include_deps_file, &syntax_trees)?;
+    }
+
+    if let Some(depfile) = matches.get_one::<String>("depfile") {
+        let target = matches
+            .get_one::<String>("output")
+            .or_else(|| matches.get_one::<String>("split_output"))
+            .map_or("-", |x| x.as_str());
+        write_depfile(depfile, target, &syntax_trees)?;
+    }
+
+    // Keep a copy of the output to parse it again once complete.
+    let (out, verify) = match matches.get_flag("verify") {
+        true => {
+            let writer = CaptureWriter::tee(out);
+            let buffer = writer.buffer();
+            (Box::new(writer) as Box<dyn Write>, Some(buffer))
+        }
+        false => (out, None),
+    };
+
+    // Compute the checksum while the pickle is streamed to the output.
+    let (out, checksum) = match matches.get_one::<String>("pickle_checksum") {
+        Some(_) => {
+            let writer = ChecksumWriter::new(out);
+            let hasher = writer.hasher();
+            (Box::new(writer) as Box<dyn Write>, Some(hasher))
+        }
+        None => (out, None),
+    };
+
+    // Only declarations differing from the baseline are emitted, capture the full pickle first.
+    let (out, baseline) = match matches.get_one::<String>("baseline") {
+        Some(baseline) => {
+            let writer = CaptureWriter::new();
+            let buffer = writer.buffer();
+            (
+                Box::new(writer) as Box<dyn Write>,
+                Some((baseline, buffer, out)),
+            )
+        }
+        None => (out, None),
+    };
+
+    // Remember which files could be parsed, before the syntax trees are consumed.
+    let parsed_files: Option<HashSet<String>> = matches
+        .contains_id("parsed_file_list")
+        .then(|| syntax_trees.iter().map(|pf| pf.path.clone()).collect());
+    let includes: Option<HashMap<String, Vec<String>>> =
+        matches.get_flag("include_only_reachable_incdirs").then(|| {
+            syntax_trees
+                .iter()
+                .map(|pf| (pf.path.clone(), pf.includes.clone()))
+                .collect()
+        });
+
+    let options = PickleOptions {
+        prefix: matches.get_one::<String>("prefix"),
+        suffix: matches.get_one::<String>("suffix"),
         exclude_rename,
         exclude,
-        library_bundle,
-        syntax_trees,
-        out,
-        matches.get_one::<String>("top_module"),
-        matches.get_flag("keep_defines"),
-        matches.get_flag("propagate_defines"),
-        !matches.get_flag("keep_timeunits"),
-    )?;
+        exclude_kinds,
+        top_module: matches.get_one::<String>("top_module"),
+        keep_defines: matches.get_flag("keep_defines"),
+        propagate_defines: matches.get_flag("propagate_defines"),
+        remove_timeunits: !matches.get_flag("keep_timeunits"),
+        split_output: matches.get_one::<String>("split_output"),
+        annotate_sources: matches.get_flag("annotate_sources"),
+        dedupe_blank_decls: matches.get_flag("dedupe_blank_decls"),
+        allow_duplicate_modules: matches.get_flag("allow_duplicate_modules"),
+        flatten_single_use_packages: matches.get_flag("flatten_single_use_packages"),
+        timescale: matches.get_one::<String>("emit_timescale"),
+        banner: banner.as_ref(),
+        no_header: matches.get_flag("no_header"),
+        no_timestamp: matches.get_flag("no_timestamp"),
+        rename_instances: matches.get_flag("rename_instances"),
+        exclude_files,
+        comment_style,
+        error_on_cyclic: matches.get_flag("error_on_cyclic"),
+        error_on_undefined: matches.get_flag("error_on_undefined"),
+        dedup_global_imports: matches.get_flag("dedup_global_imports"),
+        ordered_flist: matches.get_one::<String>("ordered_flist"),
+        quiet: matches.get_flag("quiet"),
+        toc: matches.get_flag("toc"),
+        strip_dpi: matches.get_flag("strip_dpi"),
+        expand_dot_star: matches.get_flag("expand_dot_star"),
+        max_line_width: matches.get_one::<usize>("max_line_width").copied(),
+        expand_implicit_ports: matches.get_flag("expand_implicit_ports"),
+        exclude_paths: matches
+            .get_many::<String>("exclude_path")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect(),
+        normalize_case: match matches
+            .get_one::<String>("normalize_case")
+            .map(|x| x.as_str())
+        {
+            Some("lower") => Some(NormalizeCase::Lower),
+            Some("upper") => Some(NormalizeCase::Upper),
+            _ => None,
+        },
+        exclude_prune: matches.get_flag("exclude_prune"),
+        pickle_format: match matches
+            .get_one::<String>("sort_output")
+            .or_else(|| matches.get_one::<String>("pickle_format"))
+            .map(|x| x.as_str())
+        {
+            Some("classic") | Some("file") => PickleFormat::Classic,
+            Some("alpha") => PickleFormat::Alpha,
+            _ => PickleFormat::Graph,
+        },
+        dry_run: matches.get_flag("dry_run"),
+        graph_only: matches.get_flag("list_modules")
+            || matches
+                .get_one::<String>("modules_instantiated_in")
+                .is_some(),
+    };
+
+    let mut pickle = do_pickle(options, library_bundle, syntax_trees, out)?;
+
+    if matches.get_flag("dry_run") {
+        info!(
+            "Dry run: {} modules, no errors found",
+            pickle.module_graph.node_count()
+        );
+        return Ok(());
+    }
+
+    if let Some(file) = matches.get_one::<String>("modules_instantiated_in") {
+        write_file_dependencies(&pickle, file, io::stdout())?;
+        return Ok(());
+    }
+
+    if matches.get_flag("list_modules") {
+        write_module_list(&pickle, io::stdout())?;
+        return Ok(());
+    }
+
+    if let Some(stats) = &pickle.stats {
+        if matches.get_flag("stats") {
+            eprintln!("Pickled {}", stats);
+        } else {
+            info!("Pickled {}", stats);
+        }
+        if let Some(stats_file) = matches.get_one::<String>("stats_json") {
+            write_stats(stats, stats_file)?;
+        }
+    }
+
+    if let Some((baseline, buffer, out)) = baseline {
+        let pickled = String::from_utf8_lossy(&buffer.lock().unwrap()).into_owned();
+        write_pickle_diff(baseline, &pickled, out)?;
+    }
+
+    if let Some(buffer) = verify {
+        verify_pickle(&String::from_utf8_lossy(&buffer.lock().unwrap()))?;
+        info!("Verified the generated pickle");
+    }
+
+    if let (Some(checksum_file), Some(hasher)) =
+        (matches.get_one::<String>("pickle_checksum"), checksum)
+    {
+        write_checksum(
+            checksum_file,
+            &hasher,
+            matches
+                .get_one::<String>("output")
+                .map_or("-", |x| x.as_str()),
+        )?;
+    }
+
+    if let Some(max_depth) = matches.get_one::<usize>("max_depth_warn") {
+        pickle.check_max_depth(*max_depth);
+    }
+
+    if let Some(undefined_file) = matches.get_one::<String>("undefined_out") {
+        write_undefined_modules(&pickle, undefined_file)?;
+    }
+
+    let top_module = matches
+        .get_one::<String>("top_module")
+        .map(|x| pickle.resolve_top(x))
+        .transpose()?;
+
+    if let Some(hierarchy_file) = matches.get_one::<String>("hierarchy") {
+        let top = top_module.as_ref().unwrap();
+        write_hierarchy(&pickle, hierarchy_file, top)?;
+    }
+
+    if matches.get_flag("graph_root_only") {
+        pickle.collapse_graph_to_roots();
+    }
 
     if let Some(graph_file) = matches.get_one::<String>("graph_file") {
         write_dot_graph(&pickle, graph_file)?;
     }
 
+    if let Some(graph_file) = matches.get_one::<String>("graph_graphml") {
+        write_graphml_graph(&pickle, graph_file)?;
+    }
+
+    if let Some(graph_file) = matches.get_one::<String>("graph_json") {
+        write_json_graph(&pickle, graph_file)?;
+    }
+
+    if let (Some(parsed_file_list), Some(parsed_files)) =
+        (matches.get_one::<String>("parsed_file_list"), &parsed_files)
+    {
+        write_parsed_file_list(parsed_file_list, &pickle, &file_list, parsed_files)?;
+    }
+
     // if the user requested a manifest we need to compute the information and output it in json
     // form
     if let Some(manifest_file) = matches.get_one::<String>("manifest") {
@@ -448,9 +1252,108 @@ fn main() -> Result<()> {
             file_list,
             stdin_incdirs,
             stdin_defines,
-            matches.get_one::<String>("top_module"),
+            top_module.as_ref(),
+            matches.get_flag("keep_only_tops"),
+            includes.as_ref(),
         )?;
     }
 
     Ok(())
 }
+
+// Read the lines of a file list. Nested `-f` file lists are expanded recursively, relative to the
+// including file list, and `-v` library files are collected into `library_paths`.
+fn read_flist(
+    path: &Path,
+    relative: bool,
+    visited: &mut HashSet<PathBuf>,
+    library_paths: &mut Vec<PathBuf>,
+) -> Vec<String> {
+    if !visited.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf())) {
+        warn!(
+            "Skipping repeated inclusion of file list `{}`",
+            path.display()
+        );
+        return vec![];
+    }
+    let file = File::open(path).unwrap_or_else(|e| {
+        eprintln!("error opening `{}`: {}", path.display(), e);
+        process::exit(1)
+    });
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let resolve = |line: &str| {
+        if relative {
+            resolve_flist_line(line, base)
+        } else {
+            line.to_string()
+        }
+    };
+
+    let mut lines = vec![];
+    for line in BufReader::new(file).lines().filter_map(|x| x.ok()) {
+        let line = line.trim();
+        // Skip blank lines and comments.
+        if line.is_empty() || line.starts_with("//") || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some(("-f", nested)) => lines.extend(read_flist(
+                &base.join(nested.trim()),
+                relative,
+                visited,
+                library_paths,
+            )),
+            Some(("-v", library)) => library_paths.push(PathBuf::from(resolve(library.trim()))),
+            Some(("-sv", file)) => lines.push(resolve(file.trim())),
+            _ => lines.push(resolve(line)),
+        }
+    }
+    lines
+}
+
+// Merge command line defines into the defines of a manifest or file list. On conflicts, the command
+// line define is kept if `cli_wins`, otherwise the existing one.
+fn merge_defines(
+    defines: &mut HashMap<String, Option<String>>,
+    cli_defines: &HashMap<String, Option<String>>,
+    cli_wins: bool,
+) {
+    for (name, value) in cli_defines {
+        if cli_wins || !defines.contains_key(name) {
+            defines.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+// Read a file of `NAME=VALUE` or bare `NAME` lines as given to `-D`. Lines starting with `#` are
+// comments.
+fn read_defines_file(path: &Path) -> Result<Vec<(String, Option<String>)>> {
+    let file =
+        File::open(path).map_err(|e| anyhow!("error opening `{}`: {}", path.display(), e))?;
+    let mut defines = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        defines.push(match line.split_once('=') {
+            Some((name, value)) => (name.trim().to_string(), Some(value.trim().to_string())),
+            None => (line.to_string(), None),
+        });
+    }
+    Ok(defines)
+}
+
+// Resolve a relative file or `+incdir+` path of a file list line against `base`.
+fn resolve_flist_line(line: &str, base: &Path) -> String {
+    let (directive, path) = match line.strip_prefix("+incdir+") {
+        Some(path) => ("+incdir+", path),
+        None if line.starts_with('+') => return line.to_string(),
+        None => ("", line),
+    };
+    if path.is_empty() || Path::new(path).is_absolute() {
+        return line.to_string();
+    }
+    format!("{}{}", directive, base.join(path).display())
+}
@@ -0,0 +1,4 @@
+module baseline_a;
+  logic b; endmodule
+module baseline_b;
+endmodule
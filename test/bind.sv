@@ -0,0 +1,11 @@
+module dut (
+    input logic clk_i
+);
+endmodule
+
+module top;
+    logic clk_i;
+    dut i_dut (.clk_i);
+endmodule
+
+bind dut dut_checker i_checker (.*);
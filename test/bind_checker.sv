@@ -0,0 +1,4 @@
+module dut_checker (
+    input logic clk_i
+);
+endmodule
@@ -0,0 +1,5 @@
+module Foo;
+endmodule
+
+module foo;
+endmodule
@@ -0,0 +1,17 @@
+module cfg_leaf_rtl;
+endmodule
+
+module cfg_leaf_gate;
+endmodule
+
+module cfg_top;
+    cfg_leaf_rtl i_leaf ();
+endmodule
+
+module cfg_unused;
+endmodule
+
+config cfg;
+    design work.cfg_top;
+    cell cfg_leaf_rtl use cfg_leaf_gate;
+endconfig
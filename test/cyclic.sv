@@ -0,0 +1,7 @@
+module cyclic_a;
+    cyclic_b i_b ();
+endmodule
+
+module cyclic_b;
+    cyclic_a i_a ();
+endmodule
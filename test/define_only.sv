@@ -0,0 +1,5 @@
+`include "define_only.svh"
+
+module define_only;
+    logic [`DEFINE_ONLY_WIDTH-1:0] data;
+endmodule
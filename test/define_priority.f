@@ -0,0 +1,2 @@
++define+WIDTH=4
+test/define_priority.sv
@@ -0,0 +1,3 @@
+module define_priority;
+    localparam int Width = `WIDTH;
+endmodule
@@ -42,6 +42,16 @@ module fifo #(
     word_t magic;
 endmodule
 
+///
+/// A **register** with `N` bits.
+///
+/// Stores the data until the next clock edge.
+module doc_reg #(
+    /// The reset value.
+    parameter fifo_pkg::too_much_data_t Init = '0
+);
+endmodule
+
 /// A helper package for the fifo.
 ///
 /// This is pretty awesome, you may want to consider using this.
@@ -54,4 +64,13 @@ package fifo_pkg;
 
     /// Local communities!
     localparam int Width = 42;
+
+    /// Compute the parity of a word.
+    function automatic logic parity(input logic [31:0] data);
+        return ^data;
+    endfunction
+
+    /// Wait for a number of cycles.
+    task wait_cycles(input int n);
+    endtask
 endpackage
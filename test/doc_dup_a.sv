@@ -0,0 +1,4 @@
+/// Narrow configuration.
+package doc_dup_pkg;
+    parameter int Width = 8;
+endpackage
@@ -0,0 +1,3 @@
+package doc_dup_pkg;
+    parameter int Width = 64;
+endpackage
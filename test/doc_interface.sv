@@ -0,0 +1,13 @@
+/// A simple handshake interface.
+interface handshake_intf #(
+    /// Width of the payload.
+    parameter int Width = 8
+);
+    logic valid, ready;
+    logic [Width-1:0] data;
+
+    /// The sending side.
+    modport tx (output valid, output data, input ready);
+    /// The receiving side.
+    modport rx (input valid, input data, output ready);
+endinterface
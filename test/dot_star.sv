@@ -0,0 +1,16 @@
+interface dot_star_if;
+    logic valid;
+    modport out (output valid);
+endinterface
+
+module dot_star_sink (
+    dot_star_if.out bus,
+    input logic clk_i
+);
+endmodule
+
+module dot_star_top;
+    logic clk_i;
+    dot_star_if bus ();
+    dot_star_sink i_sink (.*);
+endmodule
@@ -0,0 +1,4 @@
+module dot_star_undeclared;
+    logic clk_i;
+    undeclared_sink i_sink (.*);
+endmodule
@@ -0,0 +1,16 @@
+module wide_sink (
+    input logic data_0,
+    input logic data_1,
+    input logic data_2,
+    input logic data_3,
+    input logic data_4,
+    input logic data_5,
+    input logic data_6,
+    input logic data_7
+);
+endmodule
+
+module wide_top;
+    logic data_0, data_1, data_2, data_3, data_4, data_5, data_6, data_7;
+    wide_sink i_sink (.*);
+endmodule
@@ -0,0 +1,10 @@
+module dpi_model;
+    import "DPI-C" function int dpi_read(input int addr);
+    export "DPI-C" function dpi_write;
+
+    function void dpi_write(input int data);
+    endfunction
+
+    int data;
+    initial data = dpi_read(0);
+endmodule
@@ -0,0 +1,4 @@
+module dup_param #(
+    parameter int unsigned Width = 16
+);
+endmodule
@@ -0,0 +1,14 @@
+`ifdef TARGET_A
+`define ELSIF_WIDTH 1
+module elsif_a;
+endmodule
+`elsif TARGET_B
+`define ELSIF_WIDTH 2
+module elsif_b;
+    logic [`ELSIF_WIDTH-1:0] data;
+endmodule
+`else
+`define ELSIF_WIDTH 3
+module elsif_c;
+endmodule
+`endif
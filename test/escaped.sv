@@ -0,0 +1,14 @@
+module \my+module (
+    input logic a
+);
+endmodule
+
+module escaped_top;
+    logic a;
+    \my+module i_mod (.a(a));
+endmodule
+
+module \escaped.top ;
+    logic a;
+    \my+module i_mod (.a(a));
+endmodule
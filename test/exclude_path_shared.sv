@@ -0,0 +1,11 @@
+module xp_leaf;
+endmodule
+
+module xp_mid;
+    xp_leaf i_leaf ();
+endmodule
+
+module xp_top;
+    xp_mid i_mid_0 ();
+    xp_mid i_mid_1 ();
+endmodule
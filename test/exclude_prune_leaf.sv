@@ -0,0 +1,2 @@
+module ep_vendor_leaf;
+endmodule
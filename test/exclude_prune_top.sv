@@ -0,0 +1,7 @@
+module ep_top;
+    ep_vendor i_vendor ();
+    ep_shared i_shared ();
+endmodule
+
+module ep_shared;
+endmodule
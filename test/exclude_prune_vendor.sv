@@ -0,0 +1,4 @@
+module ep_vendor;
+    ep_vendor_leaf i_leaf ();
+    ep_shared i_shared ();
+endmodule
@@ -0,0 +1,15 @@
+extern module extern_leaf #(parameter int W = 1) (input logic clk_i);
+extern interface extern_if;
+
+module extern_leaf #(parameter int W = 1) (input logic clk_i);
+endmodule
+
+interface extern_if;
+    logic valid;
+endinterface
+
+module extern_top;
+    logic clk;
+    extern_if bus ();
+    extern_leaf i_leaf (.clk_i(clk));
+endmodule
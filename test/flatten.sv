@@ -0,0 +1,9 @@
+package flatten_pkg;
+    localparam int unsigned Width = 8;
+    typedef logic [Width-1:0] data_t;
+endpackage
+
+module flatten_top;
+    import flatten_pkg::*;
+    data_t data;
+endmodule
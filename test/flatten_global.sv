@@ -0,0 +1,2 @@
+// Imported into the compilation unit, outside of any declaration.
+import flatten_pkg::*;
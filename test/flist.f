@@ -0,0 +1,7 @@
+// Simulator-style file list.
+# Shell-style comments are skipped as well.
++incdir+test
++define+FLIST_DEFINE
+
+test/package.sv
+test/package_import.sv
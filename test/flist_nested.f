@@ -0,0 +1,2 @@
+-f flist.f
+-sv test/instantiation.sv
@@ -0,0 +1,2 @@
++incdir+inc
+relative.sv
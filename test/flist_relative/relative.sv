@@ -0,0 +1,5 @@
+`include "relative.svh"
+
+module flist_relative;
+    logic [`RELATIVE_WIDTH-1:0] data;
+endmodule
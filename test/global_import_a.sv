@@ -0,0 +1,8 @@
+package global_pkg;
+    localparam int Width = 4;
+endpackage
+
+import global_pkg::*;
+
+module global_a;
+endmodule
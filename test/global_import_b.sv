@@ -0,0 +1,5 @@
+import global_pkg::*;
+
+module global_b;
+    logic [Width-1:0] data;
+endmodule
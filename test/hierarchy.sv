@@ -0,0 +1,13 @@
+module hier_leaf #(
+    parameter int W = 1
+);
+endmodule
+
+module hier_mid;
+    hier_leaf #(.W(1)) i_leaf ();
+endmodule
+
+module hier_top;
+    hier_mid i_mid ();
+    hier_leaf #(.W(2)) i_leaf_0 (), i_leaf_1 ();
+endmodule
@@ -0,0 +1,20 @@
+`define ENABLE_FOO
+
+`ifdef ENABLE_FOO
+module ifdef_foo;
+endmodule
+`else
+module ifdef_no_foo;
+endmodule
+`endif
+
+`ifndef ENABLE_BAR
+module ifdef_no_bar;
+`ifdef ENABLE_BAZ
+    // Only a comment.
+`endif
+endmodule
+`elsif ENABLE_FOO
+module ifdef_bar_foo;
+endmodule
+`endif
@@ -0,0 +1,11 @@
+module implicit_sink (
+    input  logic clk_i,
+    input  logic rst_ni,
+    output logic data_o
+);
+endmodule
+
+module implicit_top;
+    logic clk_i, rst_ni;
+    implicit_sink i_sink (.clk_i, .rst_ni, .data_o());
+endmodule
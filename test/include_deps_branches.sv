@@ -0,0 +1,12 @@
+`define MANIFEST_HEADER "manifest_include.svh"
+
+/* `include "include.svh" */
+module include_deps_branches;
+`ifdef INCLUDE_DEPS_UNDEFINED
+    `include "include.svh"
+    logic unused;
+`else
+    `include `MANIFEST_HEADER
+    logic [`MANIFEST_WIDTH-1:0] data;
+`endif
+endmodule
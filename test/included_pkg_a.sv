@@ -0,0 +1,5 @@
+`include "included_pkg.svh"
+
+module included_pkg_a;
+    logic [included_pkg::Width-1:0] data;
+endmodule
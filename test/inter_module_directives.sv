@@ -0,0 +1,11 @@
+module directives_a;
+endmodule
+
+`default_nettype none
+`define DIRECTIVES_WIDTH 4
+
+module directives_b;
+    logic [`DIRECTIVES_WIDTH-1:0] data;
+endmodule
+
+`default_nettype wire
@@ -0,0 +1,7 @@
+module E (
+    interface.M bus
+);
+
+    D i_d (.another_interface(bus));
+
+endmodule
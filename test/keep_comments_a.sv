@@ -0,0 +1,4 @@
+module keep_comments_a;
+    // Hand-curated comment.
+    initial $display("simulation only");
+endmodule
@@ -0,0 +1,4 @@
+module keep_comments_b;
+    // This comment is stripped.
+    keep_comments_a i_a ();
+endmodule
@@ -0,0 +1,3 @@
+// Entwurf von Jos Mller
+module latin1;
+endmodule
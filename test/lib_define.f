@@ -0,0 +1,2 @@
++define+LIB_DEFINE
+test/lib_define_top.sv
@@ -0,0 +1,3 @@
+module lib_define_top;
+    lib_define i_lib_define ();
+endmodule
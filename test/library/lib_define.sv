@@ -0,0 +1,7 @@
+module lib_define;
+`ifdef LIB_DEFINE
+    logic lib_define_on;
+`else
+    logic lib_define_off;
+`endif
+endmodule
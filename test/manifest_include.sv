@@ -0,0 +1,5 @@
+`include "manifest_include.svh"
+
+module manifest_include;
+    logic [`MANIFEST_WIDTH-1:0] data;
+endmodule
@@ -0,0 +1,13 @@
+package nettype_pkg;
+    typedef struct {
+        real value;
+    } volt_t;
+
+    nettype volt_t volt_net_t;
+endpackage
+
+module nettype_user import nettype_pkg::*; (
+    input volt_net_t vin
+);
+    volt_net_t internal;
+endmodule
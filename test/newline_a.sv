@@ -0,0 +1,2 @@
+module newline_a;
+endmodule
\ No newline at end of file
@@ -0,0 +1,8 @@
+
+
+
+module newline_b;
+endmodule
+
+
+
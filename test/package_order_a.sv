@@ -0,0 +1,3 @@
+package order_a_pkg;
+    typedef logic [31:0] word_t;
+endpackage
@@ -0,0 +1,4 @@
+package order_b_pkg;
+    import order_a_pkg::*;
+    typedef word_t [1:0] double_t;
+endpackage
@@ -0,0 +1,7 @@
+module pragma;
+    // verilator lint_off UNUSED
+    logic unused; // This comment is stripped.
+    // verilator lint_on UNUSED
+    /* synthesis syn_keep = 1 */
+    /* Stripped as well. */
+endmodule
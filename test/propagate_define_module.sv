@@ -0,0 +1,4 @@
+`define DEPTH 4
+
+module propagate_depth_unused;
+endmodule
@@ -0,0 +1,3 @@
+module propagate_depth_top;
+    logic [`DEPTH-1:0] data;
+endmodule
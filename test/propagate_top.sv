@@ -0,0 +1,6 @@
+module propagate_top;
+    logic [`WIDTH-1:0] data;
+endmodule
+
+module propagate_unused;
+endmodule
@@ -0,0 +1,5 @@
+module foo;
+endmodule
+
+module p_foo;
+endmodule
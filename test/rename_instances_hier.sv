@@ -0,0 +1,12 @@
+module hier_leaf #(
+    parameter int Width = 1
+);
+    logic sig;
+endmodule
+
+module hier_top;
+    hier_leaf u_leaf ();
+    defparam u_leaf.Width = 2;
+    logic probe;
+    assign probe = u_leaf.sig;
+endmodule
@@ -0,0 +1,6 @@
+module common;
+endmodule
+
+package common;
+    localparam int Width = 8;
+endpackage
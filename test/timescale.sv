@@ -0,0 +1,6 @@
+`timescale 1ns/1ps
+
+module ts_top;
+    timeunit 1ns;
+    timeprecision 1ps;
+endmodule
@@ -0,0 +1 @@
+`undefineall
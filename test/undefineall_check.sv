@@ -0,0 +1,7 @@
+`ifdef WIDTH
+module undefineall_stale;
+endmodule
+`else
+module undefineall_clean;
+endmodule
+`endif
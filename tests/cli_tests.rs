@@ -40,6 +40,223 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_doc_headline() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc.sv").arg("--doc").arg(dir.path());
+
+        cmd.assert().success();
+
+        // The headline is the first non-empty line of the documentation.
+        let index = std::fs::read_to_string(dir.path().join("index.html"))?;
+        assert!(index.contains("A <strong>register</strong> with <code>N</code> bits."));
+        assert!(!index.contains("Stores the data"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_omitted() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let unparseable = dir.path().join("broken&unparseable.sv");
+        std::fs::write(&unparseable, "module broken (;\nendmodule\n")?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc.sv")
+            .arg(&unparseable)
+            .arg("-i")
+            .arg("--doc")
+            .arg(dir.path().join("doc"));
+
+        cmd.assert().success();
+
+        // The path of the omitted file is escaped.
+        let index = std::fs::read_to_string(dir.path().join("doc/index.html"))?;
+        assert!(index.contains("Omitted Files"));
+        assert!(index.contains("broken&amp;unparseable.sv</code></li>"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_duplicates() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc_dup_a.sv")
+            .arg("test/doc_dup_b.sv")
+            .arg("--doc")
+            .arg(dir.path());
+
+        // Warnings are logged to stdout.
+        cmd.assert().success().stdout(predicate::str::contains(
+            "Conflicting declarations of `doc_dup_pkg`, documenting the first one",
+        ));
+
+        let index = std::fs::read_to_string(dir.path().join("index.html"))?;
+        assert_eq!(
+            index.matches("href=\"package.doc_dup_pkg.html\"").count(),
+            1
+        );
+        let package = std::fs::read_to_string(dir.path().join("package.doc_dup_pkg.html"))?;
+        assert!(package.contains("Narrow configuration."));
+        assert!(package.contains("id=\"parameter.Width\""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_type_links() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc.sv").arg("--doc").arg(dir.path());
+
+        cmd.assert().success();
+
+        let fifo = std::fs::read_to_string(dir.path().join("module.fifo.html"))?;
+        assert!(fifo.contains("<a class=\"type\" href=\"type.word_t.html\">word_t</a>"));
+        let reg = std::fs::read_to_string(dir.path().join("module.doc_reg.html"))?;
+        assert!(reg.contains(
+            "<a class=\"type\" href=\"type.too_much_data_t.html\">fifo_pkg::too_much_data_t</a>"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_source_snippets() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc.sv")
+            .arg("--doc")
+            .arg(dir.path())
+            .arg("--keep-source-comments-in-docs");
+
+        cmd.assert().success();
+
+        let module = std::fs::read_to_string(dir.path().join("module.fifo.html"))?;
+        assert!(module.contains("<span class=\"kw\">module</span> fifo"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_all_items() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc.sv").arg("--doc").arg(dir.path());
+
+        cmd.assert().success();
+
+        let all = std::fs::read_to_string(dir.path().join("all.html"))?;
+        assert!(all.contains("<a class=\"module\" href=\"module.fifo.html\">fifo</a>"));
+        assert!(all.contains("<a class=\"package\" href=\"package.fifo_pkg.html\">fifo_pkg</a>"));
+        // Types nested in modules and packages are listed as well, in alphabetical order.
+        let qword = all.find("type.qword_t.html").unwrap();
+        let too_much = all.find("type.too_much_data_t.html").unwrap();
+        let word = all.find("type.word_t.html").unwrap();
+        assert!(qword < too_much && too_much < word);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_interface() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc_interface.sv")
+            .arg("--doc")
+            .arg(dir.path());
+
+        cmd.assert().success();
+
+        let index = std::fs::read_to_string(dir.path().join("index.html"))?;
+        assert!(index.contains("href=\"interface.handshake_intf.html\">handshake_intf</a>"));
+        assert!(index.contains("A simple handshake interface."));
+
+        let intf = std::fs::read_to_string(dir.path().join("interface.handshake_intf.html"))?;
+        assert!(intf.contains("id=\"parameter.Width\""));
+        assert!(intf.contains("id=\"modport.tx\""));
+        assert!(intf.contains("(output valid, output data, input ready)"));
+        assert!(intf.contains("The receiving side."));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_json() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let json = dir.path().join("doc.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc.sv").arg("--doc-json").arg(&json);
+
+        cmd.assert().success();
+
+        let doc: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(json)?)?;
+        let fifo = &doc["modules"][0];
+        assert_eq!(fifo["name"], "fifo");
+        assert_eq!(fifo["content"]["params"][0]["name"], "N");
+        assert_eq!(
+            fifo["content"]["params"][0]["doc"]
+                .as_str()
+                .map(|x| x.trim()),
+            Some("Set this to a random value.")
+        );
+        assert_eq!(doc["packages"][0]["name"], "fifo_pkg");
+        assert_eq!(doc["types"][0]["name"], "word_t");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_single() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let html = dir.path().join("doc.html");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc.sv").arg("--doc-single").arg(&html);
+
+        cmd.assert().success();
+
+        let page = std::fs::read_to_string(html)?;
+        assert!(page.contains("<style>"));
+        assert!(!page.contains("static/rustdoc.css"));
+        let index = page.find("<section id=\"main\"").unwrap();
+        assert!(index < page.find("<section id=\"module-fifo\"").unwrap());
+        assert!(page.contains("<a class=\"module\" href=\"#module-fifo\">fifo</a>"));
+        assert!(page.contains("<section id=\"package-fifo_pkg\""));
+        assert!(page.contains("<section id=\"type-word_t\""));
+        assert!(page.contains("<section id=\"all\""));
+        // The ids within a section are prefixed with the id of the section.
+        assert!(page.contains("id=\"module-fifo.parameter.N\""));
+        assert!(page.contains("href=\"#module-fifo.parameter.N\""));
+        assert!(page.contains("id=\"module-fifo.ports\""));
+        assert!(page.contains("id=\"all.modules\""));
+        assert!(!page.contains("id=\"ports\""));
+        assert!(!dir.path().join("static").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_package_index() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/doc.sv").arg("--doc").arg(dir.path());
+
+        cmd.assert().success();
+
+        let package = std::fs::read_to_string(dir.path().join("package.fifo_pkg.html"))?;
+        assert!(package.contains("<a href=\"#type.too_much_data_t\">too_much_data_t</a>"));
+        assert!(package.contains("<tr id=\"type.too_much_data_t\">"));
+        assert!(
+            package.contains("<a href=\"#function.parity\">parity</a>(input logic [31:0] data)")
+        );
+        assert!(package.contains("Compute the parity of a word."));
+        assert!(package.contains("<a href=\"#task.wait_cycles\">wait_cycles</a>(input int n)"));
+        assert!(package.contains("<span class=\"type-annotation\">: int = 123</span>"));
+
+        Ok(())
+    }
+
     #[test]
     fn test_package_2() -> Result<()> {
         let mut cmd = Command::cargo_bin("morty")?;
@@ -85,4 +302,1857 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_preprocess_quiet() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/preprocess.sv")
+            .arg("-I")
+            .arg("test")
+            .arg("-E")
+            .arg("--quiet");
+
+        cmd.assert().success().stderr(predicate::str::is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preprocess_line_directives() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/preprocess.sv")
+            .arg("-I")
+            .arg("test")
+            .arg("-E")
+            .arg("--quiet")
+            .arg("--line-directives");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("include.svh\" 0\n"))
+            .stdout(predicate::str::contains(
+                "`line 3 \"test/preprocess.sv\" 0\nmodule test;",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preprocess_manifest_incdir() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let manifest = dir.path().join("manifest.json");
+        std::fs::write(
+            &manifest,
+            serde_json::json!([{
+                "include_dirs": ["test/manifest_incdir"],
+                "defines": {},
+                "files": ["test/manifest_include.sv"],
+            }])
+            .to_string(),
+        )?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f").arg(&manifest).arg("-E").arg("--quiet");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module manifest_include"))
+            .stdout(predicate::str::contains("`MANIFEST_WIDTH").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_base_dir() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let manifest = dir.path().join("manifest.json");
+        let list = dir.path().join("parsed.json");
+        std::fs::write(
+            &manifest,
+            serde_json::json!([{
+                "include_dirs": ["manifest_incdir"],
+                "base_dir": "test",
+                "defines": {},
+                "files": ["test/manifest_include.sv"],
+            }])
+            .to_string(),
+        )?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f")
+            .arg(&manifest)
+            .arg("--emit-parsed-file-list")
+            .arg(&list);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module manifest_include"));
+
+        // The include directory is resolved against the base directory.
+        let list: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(list)?)?;
+        assert_eq!(list[0]["file"], "test/manifest_include.sv");
+        assert_eq!(
+            list[0]["include_dirs"],
+            serde_json::json!(["test/manifest_incdir"])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_comments_in() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/keep_comments_a.sv")
+            .arg("test/keep_comments_b.sv")
+            .arg("--strip-comments")
+            .arg("--keep-comments-in")
+            .arg("test/keep_comments_a.sv");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("// Hand-curated comment."))
+            .stdout(predicate::str::contains("This comment is stripped").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_pragma() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/pragma.sv").arg("--strip-comments");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("// verilator lint_off UNUSED"))
+            .stdout(predicate::str::contains("/* synthesis syn_keep = 1 */"))
+            .stdout(predicate::str::contains("stripped").not())
+            .stdout(predicate::str::contains("Stripped").not());
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/pragma.sv")
+            .arg("--strip-comments")
+            .arg("--keep-pragma")
+            .arg("synthesis");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("verilator").not())
+            .stdout(predicate::str::contains("/* synthesis syn_keep = 1 */"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_output() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--split-output")
+            .arg(dir.path());
+
+        cmd.assert().success();
+
+        let module_4 = std::fs::read_to_string(dir.path().join("module_4.sv"))?;
+        assert!(module_4.contains("module module_1"));
+        assert!(!module_4.contains("module_3"));
+        let module_3 = std::fs::read_to_string(dir.path().join("module_3.sv"))?;
+        assert!(!module_3.contains("module module_1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotate_sources() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/instantiation.sv").arg("--annotate-sources");
+
+        cmd.assert().success().stdout(predicate::str::contains(
+            "// from test/instantiation.sv\nmodule module_1",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_undefined_out() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let undefined = dir.path().join("undefined.txt");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--undefined-out")
+            .arg(&undefined);
+
+        cmd.assert().success();
+
+        assert_eq!(std::fs::read_to_string(undefined)?, "module_external\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_top() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/bind.sv")
+            .arg("test/bind_checker.sv")
+            .arg("--top")
+            .arg("top");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module dut_checker"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe_blank_decls() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/package.sv").arg("--dedupe-blank-decls");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("blub_pkg").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_parameters() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/dup_param_a.sv")
+            .arg("test/dup_param_b.sv")
+            .arg("--allow-duplicate-modules");
+
+        cmd.assert().success().stdout(predicate::str::contains(
+            "Duplicate declarations of `dup_param` differ in the default of `Width`: 8 in test/dup_param_a.sv, 16 in test/dup_param_b.sv",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_single_use_packages() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/flatten.sv")
+            .arg("--flatten-single-use-packages");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("package").not())
+            .stdout(predicate::str::contains("import").not())
+            .stdout(predicate::str::contains(
+                "typedef logic [Width-1:0] data_t;",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_global_import() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/flatten.sv")
+            .arg("test/flatten_global.sv")
+            .arg("--flatten-single-use-packages");
+
+        // The file-level import still refers to the package, which is kept.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("package flatten_pkg;"))
+            .stdout(predicate::str::contains("import flatten_pkg::*;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_modules() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let flist = dir.path().join("pickled.f");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/flatten.sv")
+            .arg("--list-modules")
+            .arg("--ordered-flist")
+            .arg(&flist);
+
+        cmd.assert().success().stdout(
+            "package flatten_pkg test/flatten.sv:1\nmodule flatten_top test/flatten.sv:6\n",
+        );
+        assert!(!flist.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_json() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let graph = dir.path().join("graph.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/flatten.sv").arg("--graph-json").arg(&graph);
+
+        cmd.assert().success();
+
+        let graph: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(graph)?)?;
+        assert_eq!(
+            graph["edges"],
+            serde_json::json!([{"from": "flatten_top", "to": "flatten_pkg"}])
+        );
+        assert_eq!(graph["nodes"].as_array().unwrap().len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_graphml() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let graph = dir.path().join("graph.graphml");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/flatten.sv")
+            .arg("--graph-graphml")
+            .arg(&graph);
+
+        cmd.assert().success();
+
+        let graph = std::fs::read_to_string(graph)?;
+        let node_id = |name: &str, ty: &str| {
+            let data = format!(
+                "\"><data key=\"label\">{}</data><data key=\"type\">{}</data></node>",
+                name, ty
+            );
+            let line = graph.lines().find(|l| l.ends_with(&data)).unwrap();
+            line.trim_start()
+                .strip_prefix("<node id=\"")
+                .unwrap()
+                .strip_suffix(&data)
+                .unwrap()
+                .to_string()
+        };
+        let top = node_id("flatten_top", "module");
+        let pkg = node_id("flatten_pkg", "package");
+        assert_eq!(graph.matches("<node ").count(), 2);
+        assert_eq!(graph.matches("<edge ").count(), 1);
+        assert!(graph.contains(&format!("<edge source=\"{}\" target=\"{}\"/>", top, pkg)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_root_only() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let graph = dir.path().join("graph.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--graph-json")
+            .arg(&graph)
+            .arg("--graph-root-only");
+
+        cmd.assert().success();
+
+        // `module_2` is only instantiated below `module_1`, which is a child of `module_4`.
+        let graph: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(graph)?)?;
+        let nodes: Vec<&str> = graph["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|n| n["name"].as_str().unwrap())
+            .collect();
+        assert!(nodes.contains(&"module_4"));
+        assert!(nodes.contains(&"module_1"));
+        assert!(!nodes.contains(&"module_2"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_instance_path() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/hierarchy.sv")
+            .arg("--top")
+            .arg("hier_top/i_mid");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module hier_mid"))
+            .stdout(predicate::str::contains("module hier_leaf"))
+            .stdout(predicate::str::contains("module hier_top").not());
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/hierarchy.sv")
+            .arg("--top")
+            .arg("hier_top/i_missing");
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Instance `i_missing` of top `hier_top/i_missing` not found in `hier_top`",
+        ));
+
+        // A module name containing a `.` is not taken as instance path.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/escaped.sv").arg("--top").arg("\\escaped.top");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module \\escaped.top ;"))
+            .stdout(predicate::str::contains("module escaped_top;").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hierarchy() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let hierarchy = dir.path().join("hierarchy.txt");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/hierarchy.sv")
+            .arg("--top")
+            .arg("hier_top")
+            .arg("--hierarchy")
+            .arg(&hierarchy);
+
+        cmd.assert().success();
+
+        assert_eq!(
+            std::fs::read_to_string(hierarchy)?,
+            "hier_top\n    i_mid (hier_mid)\n        i_leaf (hier_leaf)\n    i_leaf_0 (hier_leaf)\n    i_leaf_1 (hier_leaf)\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_path() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/hierarchy.sv")
+            .arg("--top")
+            .arg("hier_top")
+            .arg("--exclude-path")
+            .arg("hier_top.i_mid");
+
+        // `hier_leaf` is still instantiated directly in the top module.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module hier_mid").not())
+            .stdout(predicate::str::contains("module hier_leaf"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_path_shared() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/hierarchy.sv")
+            .arg("--top")
+            .arg("hier_top")
+            .arg("--exclude-path")
+            .arg("hier_top/i_mid");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module hier_mid").not());
+
+        // `xp_leaf` is still instantiated in `xp_mid` along `i_mid_1`.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/exclude_path_shared.sv")
+            .arg("--top")
+            .arg("xp_top")
+            .arg("--exclude-path")
+            .arg("xp_top/i_mid_0/i_leaf");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module xp_mid"))
+            .stdout(predicate::str::contains("module xp_leaf"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_prune() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/exclude_prune_top.sv")
+            .arg("test/exclude_prune_vendor.sv")
+            .arg("test/exclude_prune_leaf.sv")
+            .arg("--exclude")
+            .arg("ep_vendor")
+            .arg("--exclude-prune");
+
+        // `ep_shared` is still used by the top module.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module ep_top"))
+            .stdout(predicate::str::contains("module ep_shared"))
+            .stdout(predicate::str::contains("module ep_vendor").not());
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/exclude_prune_top.sv")
+            .arg("test/exclude_prune_vendor.sv")
+            .arg("test/exclude_prune_leaf.sv")
+            .arg("--exclude")
+            .arg("ep_vendor");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module ep_vendor_leaf"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pickle_checksum() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let checksum = |input: &str| -> Result<String> {
+            let output = dir.path().join("pickle.sv");
+            let checksum = dir.path().join("pickle.sha256");
+            let mut cmd = Command::cargo_bin("morty")?;
+            cmd.arg(input)
+                .arg("--no-timestamp")
+                .arg("-o")
+                .arg(&output)
+                .arg("--pickle-checksum")
+                .arg(&checksum);
+            cmd.assert().success();
+            Ok(std::fs::read_to_string(checksum)?)
+        };
+
+        // The digest is followed by the name of the output, as written by `sha256sum`.
+        let modules = checksum("test/modules.sv")?;
+        let (digest, name) = modules.trim_end().split_once("  ").unwrap();
+        assert_eq!(digest.len(), 64);
+        assert!(digest
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)));
+        assert!(name.ends_with("pickle.sv"));
+
+        // The checksum is stable, but changes along with the input.
+        assert_eq!(checksum("test/modules.sv")?, modules);
+        assert_ne!(checksum("test/package.sv")?, modules);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_depfile() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let depfile = dir.path().join("pickle.d");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/package_import.sv")
+            .arg("-I")
+            .arg("test")
+            .arg("-o")
+            .arg(dir.path().join("pickle.sv"))
+            .arg("--depfile")
+            .arg(&depfile);
+
+        cmd.assert().success();
+
+        let depfile = std::fs::read_to_string(depfile)?;
+        assert!(depfile.contains("pickle.sv:"));
+        assert!(depfile.contains("  test/package_import.sv"));
+        assert!(depfile.contains("include.svh"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_depfile_define_only_header() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let depfile = dir.path().join("pickle.d");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/define_only.sv")
+            .arg("-I")
+            .arg("test")
+            .arg("--depfile")
+            .arg(&depfile);
+
+        cmd.assert().success();
+
+        // The header leaves no text in the preprocessed output, but is a dependency nonetheless.
+        let depfile = std::fs::read_to_string(depfile)?;
+        assert!(depfile.contains("  test/define_only.svh"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interface_top() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/interface.sv").arg("--top").arg("D");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("interface A"))
+            .stdout(predicate::str::contains("module D"));
+
+        let dir = assert_fs::TempDir::new()?;
+        let undefined = dir.path().join("undefined.txt");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/interface.sv")
+            .arg("test/interface_generic.sv")
+            .arg("--top")
+            .arg("E")
+            .arg("--undefined-out")
+            .arg(&undefined);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("interface A"))
+            .stdout(predicate::str::contains("module E"));
+
+        assert_eq!(std::fs::read_to_string(undefined)?, "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_timescale() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--emit-timescale")
+            .arg("1ns/1ps");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\n\n`timescale 1ns/1ps\n"));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/timescale.sv")
+            .arg("--emit-timescale")
+            .arg("1ns/1ps");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("timescale").count(1))
+            .stdout(predicate::str::contains("timeunit").not());
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--emit-timescale")
+            .arg("2ns/1ps");
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Invalid timescale `2ns/1ps`"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_banner() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--banner")
+            .arg("// Licensed under the Apache License 2.0")
+            .arg("--no-header");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::starts_with(
+                "// Licensed under the Apache License 2.0\n\n",
+            ))
+            .stdout(predicate::str::contains("Compiled by morty").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_banner_file_no_timestamp() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--banner-file")
+            .arg("test/banner.txt")
+            .arg("--no-timestamp");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::starts_with(format!(
+                "// Copyright 2024 ETH Zurich and University of Bologna.\n\
+                 // Licensed under the Apache License 2.0\n\
+                 // Compiled by morty-{}\n\n",
+                env!("CARGO_PKG_VERSION")
+            )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preprocess_no_banner() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv").arg("-E").arg("--no-banner");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Compiled by morty").not())
+            .stdout(predicate::str::contains("module module_1;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_escaped_identifier() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/escaped.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--suffix")
+            .arg("_s");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module \\p_my+module_s ("))
+            .stdout(predicate::str::contains("\\p_my+module_s i_mod"))
+            .stdout(predicate::str::contains("module p_escaped_top_s;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_defines_top() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/propagate_define.sv")
+            .arg("test/propagate_top.sv")
+            .arg("--propagate_defines")
+            .arg("--keep_defines")
+            .arg("--top")
+            .arg("propagate_top");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`define WIDTH 8"))
+            .stdout(predicate::str::contains("module propagate_top"))
+            .stdout(predicate::str::contains("propagate_unused").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_defines_top_used_define() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/propagate_define_module.sv")
+            .arg("test/propagate_depth_top.sv")
+            .arg("--propagate_defines")
+            .arg("--keep_defines")
+            .arg("--top")
+            .arg("propagate_depth_top");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("`define DEPTH 4"))
+            .stdout(predicate::str::contains("module propagate_depth_top"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_in_shared_include() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/included_pkg_a.sv")
+            .arg("test/included_pkg_b.sv")
+            .arg("-I")
+            .arg("test");
+
+        // The package included by both files is the same declaration, and only emitted once.
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let pickle = String::from_utf8(output)?;
+        assert_eq!(pickle.matches("package included_pkg;").count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_defines_undefineall() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/propagate_define.sv")
+            .arg("test/undefineall.sv")
+            .arg("test/undefineall_check.sv")
+            .arg("--propagate_defines");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module undefineall_clean"))
+            .stdout(predicate::str::contains("undefineall_stale").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gzip_source() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let manifest = dir.path().join("manifest.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/gzipped.sv.gz")
+            .arg("--manifest")
+            .arg(&manifest);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module gzipped;"));
+
+        // The manifest refers to the compressed file.
+        assert!(std::fs::read_to_string(manifest)?.contains("test/gzipped.sv.gz"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_depth_warn() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/interface.sv")
+            .arg("test/interface_generic.sv")
+            .arg("--max-depth-warn")
+            .arg("1");
+
+        // Warnings are logged to stdout.
+        cmd.assert().success().stdout(predicate::str::contains(
+            "`A` is instantiated at depth 2 below `E`, exceeding 1",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_stack_size() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("test/interface.sv")
+            .arg("--parse-stack-size")
+            .arg("16");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("interface A"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_stack_size_sequential() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("test/interface.sv")
+            .arg("--sequential")
+            .arg("--parse-stack-size")
+            .arg("16");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("interface A"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_baseline() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let baseline = dir.path().join("baseline.sv");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv").arg("-o").arg(&baseline);
+
+        cmd.assert().success();
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("test/interface.sv")
+            .arg("--baseline")
+            .arg(&baseline);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("interface A"))
+            .stdout(predicate::str::contains("module D"))
+            .stdout(predicate::str::contains("module_1").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_baseline_declaration_ranges() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let baseline = dir.path().join("baseline.sv");
+        std::fs::write(
+            &baseline,
+            "module baseline_a;\n  logic a; endmodule\nmodule baseline_b;\nendmodule\n",
+        )?;
+
+        // The end of `baseline_a` does not start a line, yet `baseline_b` is a declaration of its
+        // own and unchanged.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/baseline_inline.sv")
+            .arg("--baseline")
+            .arg(&baseline);
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("logic b; endmodule"))
+            .stdout(predicate::str::contains("baseline_b").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_elsif_chain() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/elsif.sv").arg("-D").arg("TARGET_B");
+
+        // The preprocessor resolves the conditional, only the selected branch remains.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module elsif_b"))
+            .stdout(predicate::str::contains("logic [2-1:0] data;"))
+            .stdout(predicate::str::contains("elsif_a").not())
+            .stdout(predicate::str::contains("elsif_c").not())
+            .stdout(predicate::str::contains("`define").not())
+            .stdout(predicate::str::contains("`e").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_define_from_file() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/elsif.sv")
+            .arg("--define-from-file")
+            .arg("test/defines.txt");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module elsif_b"))
+            .stdout(predicate::str::contains("elsif_a").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_on_cyclic() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/cyclic.sv").arg("--error-on-cyclic");
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Cyclic module dependencies: `cyclic_a`, `cyclic_b`",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let output = dir.path().join("pickle.sv");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--dry-run")
+            .arg("-o")
+            .arg(&output);
+
+        cmd.assert().success().stdout(predicate::str::is_empty());
+        assert!(!output.exists());
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/cyclic.sv").arg("--dry-run");
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Cyclic module dependencies"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_on_undefined() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv").arg("--error-on-undefined");
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Undefined modules: `module_external`",
+        ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/hierarchy.sv").arg("--error-on-undefined");
+
+        cmd.assert().success();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_output_alpha() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/hierarchy.sv")
+            .arg("test/cyclic.sv")
+            .arg("--sort-output")
+            .arg("alpha");
+
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let pickle = String::from_utf8(output)?;
+        assert!(pickle.find("module cyclic_a").unwrap() < pickle.find("module hier_leaf").unwrap());
+
+        // Files are sorted by their names after renaming.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/hierarchy.sv")
+            .arg("test/cyclic.sv")
+            .arg("--sort-output")
+            .arg("alpha")
+            .arg("--exclude-rename")
+            .arg("hier_leaf")
+            .arg("-p")
+            .arg("p_");
+
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let pickle = String::from_utf8(output)?;
+        assert!(
+            pickle.find("module hier_leaf").unwrap() < pickle.find("module p_cyclic_a").unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extern_declarations() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/extern.sv")
+            .arg("--top")
+            .arg("extern_top")
+            .arg("-p")
+            .arg("p_");
+
+        // The forward declarations are renamed, the definitions are kept.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "extern module p_extern_leaf #(parameter int W = 1)",
+            ))
+            .stdout(predicate::str::contains("extern interface p_extern_if;"))
+            .stdout(predicate::str::contains(
+                "\nmodule p_extern_leaf #(parameter int W = 1)",
+            ))
+            .stdout(predicate::str::contains(
+                "interface p_extern_if;\n    logic valid;",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_declarations() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/config.sv")
+            .arg("--top")
+            .arg("cfg")
+            .arg("-p")
+            .arg("p_");
+
+        // The design top and the cell selected by the config are kept, unused modules are pruned.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("config p_cfg;"))
+            .stdout(predicate::str::contains("design work.p_cfg_top;"))
+            .stdout(predicate::str::contains(
+                "cell p_cfg_leaf_rtl use p_cfg_leaf_gate;",
+            ))
+            .stdout(predicate::str::contains("module p_cfg_top;"))
+            .stdout(predicate::str::contains("module p_cfg_leaf_rtl;"))
+            .stdout(predicate::str::contains("module p_cfg_leaf_gate;"))
+            .stdout(predicate::str::contains("cfg_unused").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_kind() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/same_name.sv")
+            .arg("--exclude")
+            .arg("module:common");
+
+        // Only the module is dropped, the package of the same name remains.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("package common;"))
+            .stdout(predicate::str::contains("module common;").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_global_imports() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/global_import_a.sv")
+            .arg("test/global_import_b.sv")
+            .arg("--dedup-global-imports");
+
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let pickle = String::from_utf8(output)?;
+        assert_eq!(pickle.matches("import global_pkg::*;").count(), 1);
+        assert!(pickle.contains("module global_b;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_collision() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/rename_collision.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--exclude-rename")
+            .arg("p_foo");
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "`foo`, `p_foo` renamed to `p_foo`",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dot_star_interface_port() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/dot_star.sv")
+            .arg("--top")
+            .arg("dot_star_top")
+            .arg("--prefix")
+            .arg("p_");
+
+        // Wildcard connections are emitted verbatim, the interface of the port is kept and renamed.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("p_dot_star_sink i_sink (.*);"))
+            .stdout(predicate::str::contains("p_dot_star_if.out bus"))
+            .stdout(predicate::str::contains("interface p_dot_star_if;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_dot_star() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/dot_star.sv").arg("--expand-dot-star");
+
+        cmd.assert().success().stdout(predicate::str::contains(
+            "dot_star_sink i_sink (.bus(bus), .clk_i(clk_i));",
+        ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/dot_star_undeclared.sv")
+            .arg("--expand-dot-star");
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Cannot expand `.*` at test/dot_star_undeclared.sv:3, `undeclared_sink` is not declared",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_dot_star_max_line_width() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/dot_star_wide.sv")
+            .arg("--expand-dot-star")
+            .arg("--max-line-width")
+            .arg("60");
+
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let output = String::from_utf8(output)?;
+        let lines: Vec<_> = output.lines().filter(|x| x.contains(".data_")).collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|x| x.len() <= 60), "{:?}", lines);
+        assert!(lines[1..]
+            .iter()
+            .all(|x| x.starts_with("                      .data_")));
+        for i in 0..8 {
+            assert!(output.contains(&format!(".data_{0}(data_{0})", i)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_implicit_ports() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/implicit_ports.sv")
+            .arg("--expand-implicit-ports");
+
+        cmd.assert().success().stdout(predicate::str::contains(
+            "implicit_sink i_sink (.clk_i(clk_i), .rst_ni(rst_ni), .data_o());",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_case() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--normalize-case")
+            .arg("upper")
+            .arg("--prefix")
+            .arg("p_");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module p_MODULE_1;"))
+            .stdout(predicate::str::contains("p_MODULE_2 i_module_2();"));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/case_collision.sv")
+            .arg("--normalize-case")
+            .arg("lower");
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("`Foo`, `foo` renamed to `foo`"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_instances() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/interface.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--rename-instances");
+
+        // Interface instances are referenced like signals and keep their name.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("p_A #(.B(2)) a_interface ();"))
+            .stdout(predicate::str::contains("p_A.M another_interface"));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--rename-instances");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("p_module_2 p_i_module_2();"))
+            .stdout(predicate::str::contains("input clk_i"));
+
+        // Hierarchical references keep the original instance name.
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/rename_instances_hier.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--rename-instances");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("p_hier_leaf p_u_leaf ();"))
+            .stderr(predicate::str::contains(
+                "Hierarchical reference to instance `u_leaf` in test/rename_instances_hier.sv:9",
+            ))
+            .stderr(predicate::str::contains(
+                "Hierarchical reference to instance `u_leaf` in test/rename_instances_hier.sv:11",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("test/interface.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--verify");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module p_module_1;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_pattern() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv").arg("--exclude").arg("module_?");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module_1").not())
+            .stdout(predicate::str::contains("module_3").not())
+            .stdout(predicate::str::contains("module_4").not());
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--exclude-rename")
+            .arg("*_4");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module p_module_1;"))
+            .stdout(predicate::str::contains("module_4 #()"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_file() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/interface.sv")
+            .arg("test/interface_generic.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--exclude-file")
+            .arg(std::fs::canonicalize("test/interface.sv")?);
+
+        // Declarations of excluded files are neither emitted nor renamed.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module p_E"))
+            .stdout(predicate::str::contains("    D i_d"))
+            .stdout(predicate::str::contains("interface").count(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_order() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/package_order_b.sv")
+            .arg("test/package_order_a.sv");
+
+        cmd.assert().success().stdout(predicate::str::is_match(
+            "(?s)package order_a_pkg;.*package order_b_pkg;",
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inter_module_directives() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/inter_module_directives.sv")
+            .arg("--keep_defines")
+            .arg("--top")
+            .arg("directives_b");
+
+        // Files are pickled as a whole, hence the directives between declarations remain in place.
+        cmd.assert().success().stdout(predicate::str::is_match(
+            "(?s)module directives_a;.*`default_nettype none.*`define DIRECTIVES_WIDTH 4.*module directives_b;.*`default_nettype wire",
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pickle_format_classic() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/package_order_b.sv")
+            .arg("test/package_order_a.sv")
+            .arg("--pickle-format")
+            .arg("classic");
+
+        cmd.assert().success().stdout(predicate::str::is_match(
+            "(?s)package order_b_pkg;.*package order_a_pkg;",
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_dpi() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/dpi.sv").arg("--strip-dpi");
+
+        // Warnings are logged to stdout.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("DPI-C").not())
+            .stdout(predicate::str::contains("function void dpi_write"))
+            .stdout(predicate::str::contains(
+                "`dpi_read` is called in test/dpi.sv:9, but its DPI import is stripped",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_toc() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv").arg("--toc");
+
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let pickle = String::from_utf8(output)?;
+        let lines: Vec<&str> = pickle.lines().collect();
+        let entries: Vec<&str> = lines
+            .iter()
+            .filter_map(|x| x.strip_prefix("//   module "))
+            .collect();
+        assert_eq!(entries.len(), 4);
+        // Each entry points to the line declaring it.
+        for entry in entries {
+            let (name, line) = entry.split_once(": line ").unwrap();
+            let line: usize = line.parse()?;
+            assert!(lines[line - 1].starts_with("module"));
+            assert!(lines[line - 1].contains(name));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_comment_style() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--annotate-sources")
+            .arg("--comment-style")
+            .arg("block");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::starts_with("/* Compiled by morty-"))
+            .stdout(predicate::str::contains(
+                "/* from test/modules.sv */\nmodule module_1;",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_only_tops() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let manifest = dir.path().join("manifest.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/interface.sv")
+            .arg("test/interface_generic.sv")
+            .arg("--manifest")
+            .arg(&manifest)
+            .arg("--keep-only-tops");
+
+        cmd.assert().success();
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(manifest)?)?;
+        assert_eq!(manifest["tops"], serde_json::json!(["E"]));
+        assert_eq!(
+            manifest["sources"][0]["files"],
+            serde_json::json!(["test/interface_generic.sv"])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_only_reachable_incdirs() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let manifest = dir.path().join("manifest.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/manifest_include.sv")
+            .arg("-I")
+            .arg("test")
+            .arg("-I")
+            .arg("test/manifest_incdir")
+            .arg("--manifest")
+            .arg(&manifest)
+            .arg("--include-only-reachable-incdirs");
+
+        cmd.assert().success();
+
+        // The header only contains a define, yet its include directory is required.
+        let manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(manifest)?)?;
+        assert_eq!(
+            manifest["sources"][0]["include_dirs"],
+            serde_json::json!(["test/manifest_incdir"])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_includes() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let includes = dir.path().join("includes.txt");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/preprocess.sv")
+            .arg("-I")
+            .arg("test")
+            .arg("--list-includes")
+            .arg(&includes);
+
+        cmd.assert().success();
+
+        let resolved = std::fs::canonicalize("test/include.svh")?;
+        let includes = std::fs::read_to_string(includes)?;
+        assert!(includes.starts_with("test/preprocess.sv\n"));
+        assert!(includes.contains(&format!("include.svh -> {}", resolved.display())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_deps() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let deps = dir.path().join("deps.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/manifest_include.sv")
+            .arg("test/modules.sv")
+            .arg("test/include_deps_branches.sv")
+            .arg("-I")
+            .arg("test/manifest_incdir")
+            .arg("-I")
+            .arg("test")
+            .arg("--include-deps")
+            .arg(&deps);
+
+        cmd.assert().success();
+
+        // Includes in comments and in branches which were not taken are omitted.
+        let deps: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(deps)?)?;
+        assert_eq!(
+            deps,
+            serde_json::json!({
+                "test/manifest_include.sv": ["test/manifest_incdir/manifest_include.svh"],
+                "test/modules.sv": [],
+                "test/include_deps_branches.sv": ["test/manifest_incdir/manifest_include.svh"],
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_flist() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let flist = dir.path().join("flist.f");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/package_order_b.sv")
+            .arg("test/package_order_a.sv")
+            .arg("--ordered-flist")
+            .arg(&flist);
+
+        cmd.assert().success();
+
+        // The package is declared before its use.
+        assert_eq!(
+            std::fs::read_to_string(flist)?,
+            "test/package_order_a.sv\ntest/package_order_b.sv\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_library_defines() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f")
+            .arg("test/lib_define.f")
+            .arg("--library-dir")
+            .arg("test/library");
+
+        // The library module is parsed with the `+define+` of the file list.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module lib_define;"))
+            .stdout(predicate::str::contains("lib_define_on"))
+            .stdout(predicate::str::contains("lib_define_off").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_parsed_file_list() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let list = dir.path().join("parsed.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/package.sv")
+            .arg("test/package_import_2.sv")
+            .arg("-I")
+            .arg("test")
+            .arg("-D")
+            .arg("FOO=1")
+            .arg("--emit-parsed-file-list")
+            .arg(&list);
+
+        cmd.assert().success();
+
+        let list: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(list)?)?;
+        assert_eq!(list[0]["file"], "test/package.sv");
+        assert_eq!(list[1]["file"], "test/package_import_2.sv");
+        assert_eq!(list[1]["include_dirs"], serde_json::json!(["test"]));
+        assert_eq!(list[1]["defines"]["FOO"], "1");
+        assert_eq!(list[1]["library"], false);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_separation() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/newline_a.sv").arg("test/newline_b.sv");
+
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let pickled = String::from_utf8(output)?;
+        let body = &pickled[pickled.find("module").unwrap()..];
+        assert!(body.contains("endmodule\n\nmodule newline_b"));
+        assert!(body.ends_with("endmodule\n"));
+        for gap in body.split("endmodule").skip(1) {
+            let gap = &gap[..gap.find("module").unwrap_or(gap.len())];
+            let newlines = gap.matches('\n').count();
+            assert!(
+                (1..=3).contains(&newlines),
+                "modules separated by {:?}",
+                gap
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_encoding() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/latin1.sv");
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "Failed to decode `test/latin1.sv`",
+        ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/latin1.sv")
+            .arg("--input-encoding")
+            .arg("latin1");
+
+        // The pickle is always written in UTF-8.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("// Entwurf von José Müller"))
+            .stdout(predicate::str::contains("module latin1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_warn_undefined_defines() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/ifdef_report.sv")
+            .arg("-D")
+            .arg("ENABLE_BAR")
+            .arg("--warn-undefined-defines");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "Macro `ENABLE_BAZ` tested at test/ifdef_report.sv:13 is never defined",
+            ))
+            .stdout(predicate::str::contains("`ENABLE_BAR` tested").not())
+            .stdout(predicate::str::contains("`ENABLE_FOO` tested").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ifdef_report() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let report = dir.path().join("ifdef.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/ifdef_report.sv")
+            .arg("--ifdef-report")
+            .arg(&report);
+
+        cmd.assert().success();
+
+        let report: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(report)?)?;
+        let conditionals = &report[0]["conditionals"];
+        assert_eq!(report[0]["file"], "test/ifdef_report.sv");
+        assert_eq!(conditionals[0]["directive"], "`ifdef ENABLE_FOO");
+        assert_eq!(conditionals[0]["line"], 3);
+        assert_eq!(conditionals[0]["taken"], true);
+        assert_eq!(conditionals[1]["directive"], "`else");
+        assert_eq!(conditionals[1]["taken"], false);
+        assert_eq!(conditionals[2]["directive"], "`ifndef ENABLE_BAR");
+        assert_eq!(conditionals[2]["taken"], true);
+        // The branch only holds a comment, hence cannot be told apart.
+        assert_eq!(conditionals[3]["directive"], "`ifdef ENABLE_BAZ");
+        assert_eq!(conditionals[3]["taken"], serde_json::Value::Null);
+        assert_eq!(conditionals[4]["directive"], "`elsif ENABLE_FOO");
+        assert_eq!(conditionals[4]["taken"], false);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let stats = dir.path().join("stats.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--prefix")
+            .arg("p_")
+            .arg("--exclude")
+            .arg("module_3")
+            .arg("--stats")
+            .arg("--stats-json")
+            .arg(&stats);
+
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("Pickled 3 modules, 0 interfaces"));
+
+        let stats: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(stats)?)?;
+        assert_eq!(stats["modules"], 3);
+        assert_eq!(stats["packages"], 0);
+        assert_eq!(stats["renames"], 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_defines() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let defines = dir.path().join("defines.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/propagate_define.sv")
+            .arg("test/propagate_top.sv")
+            .arg("--propagate_defines")
+            .arg("--dump-defines")
+            .arg(&defines);
+
+        cmd.assert().success();
+
+        let defines: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(defines)?)?;
+        assert_eq!(defines["test/propagate_top.sv"]["WIDTH"], "8");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_modules() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/instantiation.sv").arg("test/modules.sv");
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "`module_1` declared in test/instantiation.sv:7 and test/modules.sv:1",
+        ));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/instantiation.sv")
+            .arg("test/modules.sv")
+            .arg("--allow-duplicate-modules");
+
+        cmd.assert().success();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flist_relative() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("-f")
+            .arg("test/flist_relative/relative.f")
+            .arg("--flist-relative");
+
+        // Both the file and the include directory are resolved next to the file list.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module flist_relative;"))
+            .stdout(predicate::str::contains("4-1:0"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nettype() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/nettype.sv").arg("-p").arg("pre_");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("nettype volt_t volt_net_t;"))
+            .stdout(predicate::str::contains("import pre_nettype_pkg::*;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_modules_instantiated_in() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let flist = dir.path().join("pickled.f");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("test/modules.sv")
+            .arg("--modules-instantiated-in")
+            .arg("test/modules.sv")
+            .arg("--ordered-flist")
+            .arg(&flist);
+
+        cmd.assert().success().stdout(
+            "declares: module_1 module_2 module_3 module_4\ninstantiates: module_external\n",
+        );
+        assert!(!flist.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flist() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let list = dir.path().join("parsed.json");
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("--flist")
+            .arg("test/flist.f")
+            .arg("--emit-parsed-file-list")
+            .arg(&list);
+
+        // Comments and blank lines are skipped, the include resolves through `+incdir+`.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("package blub_pkg;"))
+            .stdout(predicate::str::contains("module bla;"))
+            .stdout(predicate::str::contains("module lala;"));
+
+        let list: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(list)?)?;
+        let files: Vec<&str> = list
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["file"].as_str().unwrap())
+            .collect();
+        assert_eq!(files, ["test/package.sv", "test/package_import.sv"]);
+        assert_eq!(list[0]["include_dirs"], serde_json::json!(["test"]));
+        assert_eq!(
+            list[0]["defines"],
+            serde_json::json!({"FLIST_DEFINE": null})
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_define_priority() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("--flist")
+            .arg("test/define_priority.f")
+            .arg("-D")
+            .arg("WIDTH=8");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Width = 8;"));
+
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("--flist")
+            .arg("test/define_priority.f")
+            .arg("-D")
+            .arg("WIDTH=8")
+            .arg("--define-priority")
+            .arg("file");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Width = 4;"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flist_nested() -> Result<()> {
+        let mut cmd = Command::cargo_bin("morty")?;
+        cmd.arg("--flist").arg("test/flist_nested.f");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("module blib"))
+            .stdout(predicate::str::contains("module module_1"));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,42 @@
+// Copyright 2022 PULP-platform
+
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+use morty::apply_replacements;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contained_replacement() -> Result<()> {
+        let source = "module a;\n  b i_b();\nendmodule\n";
+        let replaced = apply_replacements(
+            "test.sv",
+            source,
+            vec![
+                (12, 1, "p_b".to_string()),
+                (0, source.len(), "".to_string()),
+            ],
+        )?;
+        assert_eq!(replaced, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlapping_replacement() -> Result<()> {
+        let source = "module a;\n  b i_b();\nendmodule\n";
+        let err = apply_replacements(
+            "test.sv",
+            source,
+            vec![(7, 5, "x".to_string()), (10, 4, "y".to_string())],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Overlapping replacements at test.sv:1 (\"a;\\n  \") and test.sv:2 (\"  b \")"
+        );
+        Ok(())
+    }
+}